@@ -0,0 +1,77 @@
+/*- Benchmark for the connection registry under many simultaneous rooms.
+    Run with `cargo bench --bench registry` -*/
+
+/*- Imports -*/
+#[allow(dead_code)]
+#[path = "../src/registry.rs"]
+mod registry;
+
+use registry::ConnectionRegistry;
+use futures_channel::mpsc::unbounded;
+use tungstenite::Message;
+use std::{
+    net::{ IpAddr, Ipv4Addr, SocketAddr },
+    sync::Arc,
+    thread,
+    time::Instant,
+};
+
+/*- Constants -*/
+const ROOMS:u32 = 500;
+const PLAYERS_PER_ROOM:u32 = 5;
+const MESSAGES_PER_PLAYER:u32 = 200;
+
+/*- Main -*/
+fn main() {
+    let registry = Arc::new(ConnectionRegistry::new());
+    let addr = |room:u32, player:u32| SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), (room * PLAYERS_PER_ROOM + player) as u16 + 1);
+
+    /*- Connect everyone, keeping the receivers alive so sends succeed -*/
+    let start = Instant::now();
+    let mut receivers = Vec::new();
+    for room in 0..ROOMS {
+        for player in 0..PLAYERS_PER_ROOM {
+            let (tx, rx) = unbounded();
+            registry.insert(addr(room, player), tx);
+            registry.set_suid(&addr(room, player), &format!("suid-{room}-{player}"));
+            receivers.push(rx);
+        };
+    };
+    println!("connect    {} sockets in {:?}", ROOMS * PLAYERS_PER_ROOM, start.elapsed());
+
+    /*- Every room on its own thread: join, chat to the room, leave -*/
+    let start = Instant::now();
+    let handles:Vec<_> = (0..ROOMS).map(|room| {
+        let registry = registry.clone();
+        thread::spawn(move || {
+            for player in 0..PLAYERS_PER_ROOM {
+                registry.join_room(&addr(room, player), room);
+            };
+            let message = Message::Text(format!("room {room}"));
+            for _ in 0..MESSAGES_PER_PLAYER {
+                for player in 0..PLAYERS_PER_ROOM {
+                    registry.send_to_room(room, &message);
+                    registry.suid(&addr(room, player));
+                };
+            };
+            for player in 0..PLAYERS_PER_ROOM {
+                registry.leave_room(&addr(room, player), room);
+            };
+        })
+    }).collect();
+    for handle in handles { handle.join().unwrap() };
+
+    let elapsed = start.elapsed();
+    let deliveries = (ROOMS * PLAYERS_PER_ROOM * MESSAGES_PER_PLAYER * PLAYERS_PER_ROOM) as f64;
+    println!("rooms      {ROOMS} concurrent rooms in {elapsed:?} ({:.0} deliveries/s)", deliveries / elapsed.as_secs_f64());
+
+    /*- Disconnect everyone -*/
+    let start = Instant::now();
+    for room in 0..ROOMS {
+        for player in 0..PLAYERS_PER_ROOM {
+            registry.remove(&addr(room, player));
+        };
+    };
+    println!("disconnect {} sockets in {:?}", ROOMS * PLAYERS_PER_ROOM, start.elapsed());
+    assert_eq!(registry.len(), 0);
+}
@@ -0,0 +1,74 @@
+/*- Imports -*/
+use crate::{ config::BucketConfig, rate_limit, session::TokenBucket, ws_status };
+use argon2::{
+    Argon2,
+    password_hash::{ PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng },
+};
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use rand::{ distributions::Alphanumeric, Rng };
+
+/*- Room passwords & invite codes. Hashing is slow on purpose, so it
+    runs on the blocking pool instead of stalling the connection task -*/
+
+/*- Constants -*/
+// Guessing a password or invite code, per player across all rooms
+const JOIN_ATTEMPTS:BucketConfig = BucketConfig { burst: 5.0, per_second: 1.0 / 30.0 };
+
+const INVITE_CODE_LENGTH:usize = 10;
+
+/*- Statics -*/
+lazy_static! {
+    static ref JOIN_ATTEMPTS_BY_SUID: DashMap<String, TokenBucket> = DashMap::new();
+}
+
+/*- Functions -*/
+/*- Argon2 PHC string of a room password -*/
+pub async fn hash_password(password:String) -> Result<String, u16> {
+    let hashed = tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+    }).await;
+
+    match hashed {
+        Ok(Ok(hash)) => Ok(hash),
+        _ => Err(ws_status::INVALID_ROOM_SETTINGS)
+    }
+}
+
+/*- Check a password against the hash stored with the room -*/
+pub async fn verify_password(password:String, hash:String) -> bool {
+    tokio::task::spawn_blocking(move || {
+        match PasswordHash::new(&hash) {
+            Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+            Err(_) => false
+        }
+    }).await.unwrap_or(false)
+}
+
+/*- Compare secrets without leaking how much of them matched -*/
+pub fn constant_time_eq(expected:&str, given:&str) -> bool {
+    let (expected, given) = (expected.as_bytes(), given.as_bytes());
+    expected.len() == given.len()
+        && expected.iter().zip(given).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/*- Random code for invite links -*/
+pub fn gen_invite_code() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(INVITE_CODE_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/*- Called before every password or invite check, so that a
+    player can't keep guessing by reconnecting or switching room -*/
+pub fn take_join_attempt(suid:&str) -> Result<(), u16> {
+    match rate_limit::take(&JOIN_ATTEMPTS_BY_SUID, suid.to_string(), JOIN_ATTEMPTS) {
+        true => Ok(()),
+        false => Err(ws_status::RATE_LIMITED)
+    }
+}
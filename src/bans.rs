@@ -0,0 +1,174 @@
+/*- Imports -*/
+use crate::{ health::{ self, Dependency }, room::Room, ws_status, PeerMap, CONFIG };
+use dashmap::DashSet;
+use futures_util::TryStreamExt;
+use lazy_static::lazy_static;
+use mongodb::{ bson::{ doc, Document }, Collection, Database };
+use serde_derive::{ Serialize, Deserialize };
+use tungstenite::Message;
+use tracing::{ info, warn };
+use std::{
+    net::IpAddr,
+    sync::atomic::{ AtomicBool, Ordering },
+    time::Duration,
+};
+
+/*- Server-wide bans, managed by admins over HTTP. Stored in mongodb so
+    every instance enforces them, and cached here because they're checked
+    on every accepted socket & authentication. Other instances pick up
+    changes on their next refresh. -*/
+
+/*- Constants -*/
+const BANS_COLLECTION:&str = "server_bans";
+
+// How often the first load is retried if it fails
+const LOAD_RETRY_INTERVAL:Duration = Duration::from_secs(1);
+
+/*- Statics -*/
+// Set once the cache has been loaded, connections are refused until then
+static LOADED:AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref BANNED_SUIDS: DashSet<String> = DashSet::new();
+    static ref BANNED_IPS: DashSet<IpAddr> = DashSet::new();
+}
+
+/*- Structs, enums & unions -*/
+/*- Bans a player, an IP address or both -*/
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerBan {
+    #[serde(default)]
+    pub suid: Option<String>,
+    #[serde(default)]
+    pub ip: Option<String>,
+    #[serde(default)]
+    pub reason: Option<String>,
+
+    // Unix timestamp (seconds), set by the server
+    #[serde(default)]
+    pub created_at: u64,
+}
+
+/*- Method implementations -*/
+impl ServerBan {
+    /*- Needs a SUID or a valid IP, the IP is stored normalized -*/
+    fn validate(mut self) -> Result<Self, u16> {
+        if let Some(ip) = &self.ip {
+            match ip.parse::<IpAddr>() {
+                Ok(ip) => self.ip = Some(ip.to_string()),
+                Err(_) => return Err(ws_status::INVALID_REQUEST)
+            };
+        };
+        match (&self.suid, &self.ip) {
+            (None, None) => Err(ws_status::INVALID_REQUEST),
+            _ => Ok(self)
+        }
+    }
+
+    /*- Matches the same suid / ip -*/
+    fn filter(&self) -> Document {
+        let mut filter = Document::new();
+        if let Some(suid) = &self.suid { filter.insert("suid", suid); };
+        if let Some(ip) = &self.ip { filter.insert("ip", ip); };
+        filter
+    }
+}
+
+/*- Functions -*/
+pub fn bans(mongodb_connection:&Database) -> Collection<ServerBan> {
+    mongodb_connection.collection::<ServerBan>(BANS_COLLECTION)
+}
+
+/*- Lookups, only ever against the cache -*/
+pub fn is_suid_banned(suid:&str) -> bool {
+    BANNED_SUIDS.contains(suid)
+}
+pub fn is_ip_banned(ip:&IpAddr) -> bool {
+    BANNED_IPS.contains(ip)
+}
+pub fn is_loaded() -> bool {
+    LOADED.load(Ordering::Relaxed)
+}
+
+/*- Load the cache as soon as mongodb is up, then reload it
+    every BAN_REFRESH_INTERVAL_SECONDS -*/
+pub async fn run(mongodb_connection:Database) -> () {
+    health::wait_until_up(Dependency::Mongodb).await;
+    while let Err(status) = refresh(&mongodb_connection).await {
+        warn!(status, "Could not load server bans");
+        tokio::time::sleep(LOAD_RETRY_INTERVAL).await;
+    };
+    info!(suids = BANNED_SUIDS.len(), ips = BANNED_IPS.len(), "Loaded server bans");
+
+    /*- The first tick completes right away -*/
+    let mut interval = tokio::time::interval(CONFIG.ban_refresh_interval);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if !Dependency::Mongodb.is_up() { continue };
+
+        if let Err(status) = refresh(&mongodb_connection).await {
+            warn!(status, "Could not refresh server bans");
+        };
+    };
+}
+
+/*- Replace the cache with what's in mongodb -*/
+pub async fn refresh(mongodb_connection:&Database) -> Result<(), u16> {
+    let all = list(mongodb_connection).await?;
+    let suids:Vec<String> = all.iter().filter_map(|ban| ban.suid.clone()).collect();
+    let ips:Vec<IpAddr> = all.iter().filter_map(|ban| ban.ip.as_deref()?.parse().ok()).collect();
+
+    /*- Add before removing, so nobody banned slips through in between -*/
+    for suid in &suids { BANNED_SUIDS.insert(suid.clone()); };
+    for ip in &ips { BANNED_IPS.insert(*ip); };
+    BANNED_SUIDS.retain(|suid| suids.contains(suid));
+    BANNED_IPS.retain(|ip| ips.contains(ip));
+    LOADED.store(true, Ordering::Relaxed);
+
+    Ok(())
+}
+
+pub async fn list(mongodb_connection:&Database) -> Result<Vec<ServerBan>, u16> {
+    match bans(mongodb_connection).find(None, None).await {
+        Ok(cursor) => match cursor.try_collect().await {
+            Ok(e) => Ok(e),
+            Err(_) => Err(ws_status::DATABASE_ERROR)
+        },
+        Err(_) => Err(ws_status::DATABASE_ERROR)
+    }
+}
+
+/*- Ban, and disconnect whoever is banned right away -*/
+pub async fn add(mongodb_connection:&Database, peer_map:&PeerMap, ban:ServerBan) -> Result<ServerBan, u16> {
+    let ban = ServerBan { created_at: Room::unix_now(), ..ban.validate()? };
+    if bans(mongodb_connection).insert_one(&ban, None).await.is_err() { return Err(ws_status::DATABASE_ERROR) };
+
+    let mut addrs = Vec::new();
+    if let Some(suid) = &ban.suid {
+        BANNED_SUIDS.insert(suid.clone());
+        addrs.extend(peer_map.addrs_of_suid(suid));
+    };
+    if let Some(ip) = ban.ip.as_deref().and_then(|ip| ip.parse::<IpAddr>().ok()) {
+        BANNED_IPS.insert(ip);
+        addrs.extend(peer_map.addrs_with_ip(&ip));
+    };
+    info!(suid = ban.suid.as_deref(), ip = ban.ip.as_deref(), disconnected = addrs.len(), "Added server ban");
+    for addr in addrs {
+        peer_map.send(&addr, Message::Close(None));
+    };
+
+    Ok(ban)
+}
+
+/*- Lift every ban matching the given suid / ip -*/
+pub async fn remove(mongodb_connection:&Database, ban:ServerBan) -> Result<u64, u16> {
+    let ban = ban.validate()?;
+    let removed = match bans(mongodb_connection).delete_many(ban.filter(), None).await {
+        Ok(result) => result.deleted_count,
+        Err(_) => return Err(ws_status::DATABASE_ERROR)
+    };
+    refresh(mongodb_connection).await?;
+
+    Ok(removed)
+}
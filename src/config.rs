@@ -0,0 +1,570 @@
+/*- Imports -*/
+use serde_derive::Deserialize;
+use std::{ env, fs, fmt, net::IpAddr, path::PathBuf, str::FromStr, time::Duration };
+
+/*- Constants -*/
+// Path of the optional TOML config file, can be overridden via env
+const ENV_CONFIG_PATH:&str = "SCRAPBOX_CONFIG";
+const DEFAULT_CONFIG_PATH:&str = "config.toml";
+
+// Smaller frames would cut off legitimate requests
+const MIN_FRAME_SIZE:usize = 1024;
+
+// Admin tokens shorter than this are too easy to guess
+const MIN_ADMIN_TOKEN_LENGTH:usize = 16;
+
+/*- Structs, enums & unions -*/
+/*- Validated server configuration. Values come from (in order of
+    precedence) env / .env, the TOML config file and lastly defaults -*/
+#[derive(Debug, Clone)]
+pub struct Config {
+    /*- Mongodb -*/
+    pub mongo_host_url       : String,
+    pub mongo_database_name  : String,
+
+    /*- Account manager, always ends with a slash -*/
+    pub account_manager_url  : String,
+
+    /*- Websocket server -*/
+    pub wss_address          : String,
+    pub wss_port             : u16,
+    pub tls_cert_path        : Option<PathBuf>,
+    pub tls_key_path         : Option<PathBuf>,
+
+    /*- Largest inbound websocket message / frame, in bytes -*/
+    pub max_message_size     : usize,
+    pub max_frame_size       : usize,
+
+    /*- HTTP routes (health etc), served on the same address -*/
+    pub http_port            : u16,
+
+    /*- Origins browsers may open websockets & call HTTP routes from -*/
+    pub allowed_origins      : AllowedOrigins,
+
+    /*- Bearer token for the /admin routes, which are disabled without one -*/
+    pub admin_token          : Option<String>,
+
+    /*- Room limits -*/
+    pub default_max_players  : u8,
+    pub max_players_limit    : u8,
+
+    /*- Rooms one player may be in at once, and what happens on
+        creating / joining another one past that -*/
+    pub max_rooms_per_player : usize,
+    pub room_limit_policy    : RoomLimitPolicy,
+
+    /*- Timeouts -*/
+    pub reap_interval        : Duration,
+    pub abandoned_room_timeout: Duration,
+    pub idle_started_room_timeout: Duration,
+    pub shutdown_drain_period: Duration,
+    pub heartbeat_interval   : Duration,
+    pub pong_timeout         : Duration,
+    pub idle_timeout         : Duration,
+    pub ban_refresh_interval : Duration,
+
+    /*- Rate limits. Messages are limited per connection, IP & player,
+        room creations per connection & player -*/
+    pub connections_per_ip   : BucketConfig,
+    pub messages             : BucketConfig,
+    pub ip_messages          : BucketConfig,
+    pub suid_messages        : BucketConfig,
+    pub room_creations       : BucketConfig,
+
+    // Rate limited messages within a minute after which a connection is closed,
+    // and how long its IP may not reconnect after that
+    pub rate_limit_strikes   : u32,
+    pub rate_limit_block     : Duration,
+
+    /*- Logging, see logging.rs -*/
+    pub log_level            : String,
+    pub log_format           : LogFormat,
+}
+
+/*- What to do when a player at MAX_ROOMS_PER_PLAYER enters another room -*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RoomLimitPolicy {
+    // Refuse with TOO_MANY_ROOMS
+    Reject,
+
+    // Leave the least recently active room(s) first
+    Leave,
+}
+
+/*- How log lines are written to stdout -*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    // Human readable, the default
+    Text,
+
+    // One JSON object per line, for log collectors
+    Json,
+}
+
+/*- Compared against the Origin header browsers send. Requests without
+    one come from non-browser clients, which an origin check can't stop -*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum AllowedOrigins {
+    // "*", which is the default
+    Any,
+
+    // Exact scheme://host[:port] matches, stored lowercase
+    List(Vec<String>),
+}
+
+/*- Token bucket settings -*/
+#[derive(Debug, Clone, Copy)]
+pub struct BucketConfig {
+    pub burst                : f64,
+    pub per_second           : f64,
+}
+
+/*- Every field optional, as read from the TOML file -*/
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    mongo_host_url: Option<String>,
+    mongo_database_name: Option<String>,
+    account_manager_url: Option<String>,
+    wss_address: Option<String>,
+    wss_port: Option<u16>,
+    tls_cert_path: Option<PathBuf>,
+    tls_key_path: Option<PathBuf>,
+    http_port: Option<u16>,
+    max_message_size: Option<usize>,
+    max_frame_size: Option<usize>,
+    allowed_origins: Option<AllowedOrigins>,
+    admin_token: Option<String>,
+    default_max_players: Option<u8>,
+    max_players_limit: Option<u8>,
+    max_rooms_per_player: Option<usize>,
+    room_limit_policy: Option<RoomLimitPolicy>,
+    reap_interval_seconds: Option<u64>,
+    abandoned_room_timeout_seconds: Option<u64>,
+    idle_started_room_timeout_seconds: Option<u64>,
+    shutdown_drain_seconds: Option<u64>,
+    heartbeat_interval_seconds: Option<u64>,
+    pong_timeout_seconds: Option<u64>,
+    idle_timeout_seconds: Option<u64>,
+    ban_refresh_interval_seconds: Option<u64>,
+    connections_per_ip_burst: Option<f64>,
+    connections_per_ip_per_second: Option<f64>,
+    messages_burst: Option<f64>,
+    messages_per_second: Option<f64>,
+    ip_messages_burst: Option<f64>,
+    ip_messages_per_second: Option<f64>,
+    suid_messages_burst: Option<f64>,
+    suid_messages_per_second: Option<f64>,
+    room_creations_burst: Option<f64>,
+    room_creations_per_second: Option<f64>,
+    rate_limit_strikes: Option<u32>,
+    rate_limit_block_seconds: Option<u64>,
+    log_level: Option<String>,
+    log_format: Option<LogFormat>,
+}
+
+/*- Every problem found while loading, reported all at once -*/
+#[derive(Debug)]
+pub struct ConfigErrors(Vec<String>);
+
+/*- Method implementations -*/
+impl Config {
+    /*- Load & validate, or print every error and exit -*/
+    pub fn load_or_exit() -> Self {
+        match Self::load() {
+            Ok(config) => config,
+            Err(errors) => {
+                eprintln!("{errors}");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    /*- Load from env, config file and defaults -*/
+    pub fn load() -> Result<Self, ConfigErrors> {
+        let mut errors:Vec<String> = Vec::new();
+
+        /*- Read config file -*/
+        let file = Self::read_file(&mut errors);
+        let mut loader = Loader { errors };
+
+        /*- Collect fields -*/
+        let mongo_host_url = loader.required("MONGO_HOST_URL", file.mongo_host_url);
+        let mongo_database_name = loader.required("MONGO_DATABASE_NAME", file.mongo_database_name);
+        let account_manager_url = loader.required("ACCOUNT_MANAGER_URL", file.account_manager_url);
+        let wss_address = loader.or_default("WSS_ADDRESS", file.wss_address, "127.0.0.1".to_string());
+        let wss_port = loader.or_default("WSS_PORT", file.wss_port, 8080);
+        let tls_cert_path = loader.optional("TLS_CERT_PATH", file.tls_cert_path);
+        let tls_key_path = loader.optional("TLS_KEY_PATH", file.tls_key_path);
+        let http_port = loader.or_default("HTTP_PORT", file.http_port, 8090);
+        let max_message_size = loader.or_default("MAX_MESSAGE_SIZE", file.max_message_size, 64 * 1024);
+        let max_frame_size = loader.or_default("MAX_FRAME_SIZE", file.max_frame_size, 16 * 1024);
+        let allowed_origins = loader.or_default("ALLOWED_ORIGINS", file.allowed_origins, AllowedOrigins::Any);
+        let admin_token = loader.optional("ADMIN_TOKEN", file.admin_token);
+        let default_max_players = loader.or_default("DEFAULT_MAX_PLAYERS", file.default_max_players, 5);
+        let max_players_limit = loader.or_default("MAX_PLAYERS_LIMIT", file.max_players_limit, 16);
+        let max_rooms_per_player = loader.or_default("MAX_ROOMS_PER_PLAYER", file.max_rooms_per_player, 1);
+        let room_limit_policy = loader.or_default("ROOM_LIMIT_POLICY", file.room_limit_policy, RoomLimitPolicy::Reject);
+        let reap_interval = loader.or_default("REAP_INTERVAL_SECONDS", file.reap_interval_seconds, 30);
+        let abandoned_room_timeout = loader.or_default("ABANDONED_ROOM_TIMEOUT_SECONDS", file.abandoned_room_timeout_seconds, 5 * 60);
+        let idle_started_room_timeout = loader.or_default("IDLE_STARTED_ROOM_TIMEOUT_SECONDS", file.idle_started_room_timeout_seconds, 30 * 60);
+        let shutdown_drain = loader.or_default("SHUTDOWN_DRAIN_SECONDS", file.shutdown_drain_seconds, 10);
+        let heartbeat_interval = loader.or_default("HEARTBEAT_INTERVAL_SECONDS", file.heartbeat_interval_seconds, 15);
+        let pong_timeout = loader.or_default("PONG_TIMEOUT_SECONDS", file.pong_timeout_seconds, 45);
+        let idle_timeout = loader.or_default("IDLE_TIMEOUT_SECONDS", file.idle_timeout_seconds, 10 * 60);
+        let ban_refresh_interval = loader.or_default("BAN_REFRESH_INTERVAL_SECONDS", file.ban_refresh_interval_seconds, 60);
+        let connections_per_ip = loader.bucket("CONNECTIONS_PER_IP", file.connections_per_ip_burst, file.connections_per_ip_per_second, 10.0, 1.0);
+        let messages = loader.bucket("MESSAGES", file.messages_burst, file.messages_per_second, 20.0, 10.0);
+        let ip_messages = loader.bucket("IP_MESSAGES", file.ip_messages_burst, file.ip_messages_per_second, 60.0, 30.0);
+        let suid_messages = loader.bucket("SUID_MESSAGES", file.suid_messages_burst, file.suid_messages_per_second, 40.0, 20.0);
+        let room_creations = loader.bucket("ROOM_CREATIONS", file.room_creations_burst, file.room_creations_per_second, 3.0, 1.0 / 20.0);
+        let rate_limit_strikes = loader.or_default("RATE_LIMIT_STRIKES", file.rate_limit_strikes, 20);
+        let rate_limit_block = loader.or_default("RATE_LIMIT_BLOCK_SECONDS", file.rate_limit_block_seconds, 60);
+        let log_level = loader.or_default("LOG_LEVEL", file.log_level, "info".to_string());
+        let log_format = loader.or_default("LOG_FORMAT", file.log_format, LogFormat::Text);
+        let mut errors = loader.errors;
+
+        /*- Validate -*/
+        if let Some(url) = &mongo_host_url {
+            if !(url.starts_with("mongodb://") || url.starts_with("mongodb+srv://")) {
+                errors.push(format!("MONGO_HOST_URL must start with mongodb:// or mongodb+srv://, got {url:?}"));
+            };
+        };
+        if let Some(name) = &mongo_database_name {
+            if name.trim().is_empty() { errors.push("MONGO_DATABASE_NAME must not be empty".to_string()) };
+        };
+        if let Some(url) = &account_manager_url {
+            if !(url.starts_with("http://") || url.starts_with("https://")) {
+                errors.push(format!("ACCOUNT_MANAGER_URL must start with http:// or https://, got {url:?}"));
+            };
+        };
+        if tls_cert_path.is_some() != tls_key_path.is_some() {
+            errors.push("TLS_CERT_PATH and TLS_KEY_PATH must be set together".to_string());
+        };
+        for (key, path) in [("TLS_CERT_PATH", &tls_cert_path), ("TLS_KEY_PATH", &tls_key_path)] {
+            match path {
+                Some(path) if path.as_os_str().is_empty() => errors.push(format!("{key} must not be empty")),
+                Some(path) if !path.is_file() => errors.push(format!("{key}: no file at {}", path.display())),
+                _ => ()
+            };
+        };
+        if wss_address.parse::<IpAddr>().is_err() { errors.push(format!("WSS_ADDRESS must be an IP address, got {wss_address:?}")) };
+        if http_port == wss_port { errors.push(format!("HTTP_PORT and WSS_PORT must differ, both are {wss_port}")) };
+        if max_frame_size < MIN_FRAME_SIZE { errors.push(format!("MAX_FRAME_SIZE must be at least {MIN_FRAME_SIZE}")) };
+        if max_frame_size > max_message_size {
+            errors.push(format!("MAX_FRAME_SIZE ({max_frame_size}) must not exceed MAX_MESSAGE_SIZE ({max_message_size})"));
+        };
+        if default_max_players == 0 { errors.push("DEFAULT_MAX_PLAYERS must be at least 1".to_string()) };
+        if default_max_players > max_players_limit {
+            errors.push(format!("DEFAULT_MAX_PLAYERS ({default_max_players}) must not exceed MAX_PLAYERS_LIMIT ({max_players_limit})"));
+        };
+        if max_rooms_per_player == 0 { errors.push("MAX_ROOMS_PER_PLAYER must be at least 1".to_string()) };
+        if heartbeat_interval == 0 { errors.push("HEARTBEAT_INTERVAL_SECONDS must be at least 1".to_string()) };
+        if pong_timeout <= heartbeat_interval {
+            errors.push(format!("PONG_TIMEOUT_SECONDS ({pong_timeout}) must be longer than HEARTBEAT_INTERVAL_SECONDS ({heartbeat_interval})"));
+        };
+        if reap_interval == 0 { errors.push("REAP_INTERVAL_SECONDS must be at least 1".to_string()) };
+        if ban_refresh_interval == 0 { errors.push("BAN_REFRESH_INTERVAL_SECONDS must be at least 1".to_string()) };
+        for (key, bucket) in [
+            ("CONNECTIONS_PER_IP", connections_per_ip),
+            ("MESSAGES", messages),
+            ("IP_MESSAGES", ip_messages),
+            ("SUID_MESSAGES", suid_messages),
+            ("ROOM_CREATIONS", room_creations),
+        ] {
+            bucket.validate(key, &mut errors);
+        };
+        if rate_limit_strikes == 0 { errors.push("RATE_LIMIT_STRIKES must be at least 1".to_string()) };
+        if let Some(token) = &admin_token {
+            if token.len() < MIN_ADMIN_TOKEN_LENGTH {
+                errors.push(format!("ADMIN_TOKEN must be at least {MIN_ADMIN_TOKEN_LENGTH} characters"));
+            };
+        };
+
+        /*- Return -*/
+        match (errors.is_empty(), mongo_host_url, mongo_database_name, account_manager_url) {
+            (true, Some(mongo_host_url), Some(mongo_database_name), Some(account_manager_url)) => Ok(Self {
+                mongo_host_url,
+                mongo_database_name,
+                account_manager_url: match account_manager_url.ends_with('/') {
+                    true => account_manager_url,
+                    false => format!("{account_manager_url}/")
+                },
+                wss_address,
+                wss_port,
+                tls_cert_path,
+                tls_key_path,
+                http_port,
+                max_message_size,
+                max_frame_size,
+                allowed_origins,
+                admin_token,
+                default_max_players,
+                max_players_limit,
+                max_rooms_per_player,
+                room_limit_policy,
+                reap_interval: Duration::from_secs(reap_interval),
+                abandoned_room_timeout: Duration::from_secs(abandoned_room_timeout),
+                idle_started_room_timeout: Duration::from_secs(idle_started_room_timeout),
+                shutdown_drain_period: Duration::from_secs(shutdown_drain),
+                heartbeat_interval: Duration::from_secs(heartbeat_interval),
+                pong_timeout: Duration::from_secs(pong_timeout),
+                idle_timeout: Duration::from_secs(idle_timeout),
+                ban_refresh_interval: Duration::from_secs(ban_refresh_interval),
+                connections_per_ip,
+                messages,
+                ip_messages,
+                suid_messages,
+                room_creations,
+                rate_limit_strikes,
+                rate_limit_block: Duration::from_secs(rate_limit_block),
+                log_level,
+                log_format,
+            }),
+            _ => Err(ConfigErrors(errors))
+        }
+    }
+
+    /*- The config file is optional unless its path was set explicitly -*/
+    fn read_file(errors:&mut Vec<String>) -> ConfigFile {
+        let (path, explicit) = match env::var(ENV_CONFIG_PATH) {
+            Ok(path) => (path, true),
+            Err(_) => (DEFAULT_CONFIG_PATH.to_string(), false)
+        };
+
+        match fs::read_to_string(&path) {
+            Ok(content) => match toml::from_str::<ConfigFile>(&content) {
+                Ok(file) => file,
+                Err(e) => {
+                    errors.push(format!("{path}: {e}"));
+                    ConfigFile::default()
+                }
+            },
+            Err(e) => {
+                if explicit { errors.push(format!("{ENV_CONFIG_PATH}: could not read {path}: {e}")) };
+                ConfigFile::default()
+            }
+        }
+    }
+}
+
+/*- Reads a single field from env, falling back to the config file -*/
+struct Loader { errors: Vec<String> }
+impl Loader {
+    fn optional<T: FromStr>(&mut self, key:&str, file_value:Option<T>) -> Option<T>
+        where T::Err: fmt::Display
+    {
+        match env::var(key) {
+            Ok(value) => match value.parse::<T>() {
+                Ok(e) => Some(e),
+                Err(e) => {
+                    self.errors.push(format!("{key}: invalid value {value:?} ({e})"));
+                    None
+                }
+            },
+            Err(_) => file_value
+        }
+    }
+    fn required<T: FromStr>(&mut self, key:&str, file_value:Option<T>) -> Option<T>
+        where T::Err: fmt::Display
+    {
+        let had_env = env::var(key).is_ok();
+        let value = self.optional(key, file_value);
+        if value.is_none() && !had_env {
+            self.errors.push(format!("{key}: missing, set it in env, .env or the config file"));
+        };
+
+        value
+    }
+    fn or_default<T: FromStr>(&mut self, key:&str, file_value:Option<T>, default:T) -> T
+        where T::Err: fmt::Display
+    {
+        self.optional(key, file_value).unwrap_or(default)
+    }
+
+    /*- Reads `{prefix}_BURST` & `{prefix}_PER_SECOND` -*/
+    fn bucket(&mut self, prefix:&str, file_burst:Option<f64>, file_per_second:Option<f64>, burst:f64, per_second:f64) -> BucketConfig {
+        BucketConfig {
+            burst: self.or_default(&format!("{prefix}_BURST"), file_burst, burst),
+            per_second: self.or_default(&format!("{prefix}_PER_SECOND"), file_per_second, per_second),
+        }
+    }
+}
+
+impl BucketConfig {
+    /*- `prefix` as in Loader::bucket -*/
+    fn validate(&self, prefix:&str, errors:&mut Vec<String>) {
+        if self.burst < 1.0 { errors.push(format!("{prefix}_BURST must be at least 1")) };
+        if self.per_second.is_nan() || self.per_second <= 0.0 { errors.push(format!("{prefix}_PER_SECOND must be above 0")) };
+    }
+}
+
+impl FromStr for RoomLimitPolicy {
+    type Err = String;
+    fn from_str(value:&str) -> Result<Self, Self::Err> {
+        match value {
+            "reject" => Ok(Self::Reject),
+            "leave" => Ok(Self::Leave),
+            _ => Err("expected \"reject\" or \"leave\"".to_string())
+        }
+    }
+}
+
+/*- Same values as in env, for the config file -*/
+impl<'de> serde::Deserialize<'de> for RoomLimitPolicy {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer:D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+    fn from_str(value:&str) -> Result<Self, Self::Err> {
+        match value {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err("expected \"text\" or \"json\"".to_string())
+        }
+    }
+}
+
+/*- Same values as in env, for the config file -*/
+impl<'de> serde::Deserialize<'de> for LogFormat {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer:D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl AllowedOrigins {
+    pub fn allows(&self, origin:&str) -> bool {
+        match self {
+            Self::Any => true,
+            Self::List(origins) => origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin))
+        }
+    }
+
+    /*- "*" anywhere in the list allows every origin -*/
+    fn from_list(origins:Vec<String>) -> Result<Self, String> {
+        let mut list = Vec::new();
+        for origin in origins.iter().map(|origin| origin.trim()).filter(|origin| !origin.is_empty()) {
+            if origin == "*" { return Ok(Self::Any) };
+
+            let host = match origin.strip_prefix("https://").or_else(|| origin.strip_prefix("http://")) {
+                Some(host) => host,
+                None => return Err(format!("{origin:?} must start with http:// or https://"))
+            };
+            if host.is_empty() || host.contains('/') {
+                return Err(format!("{origin:?} must be scheme://host[:port], without a path"));
+            };
+            list.push(origin.to_ascii_lowercase());
+        };
+
+        match list.is_empty() {
+            true => Err("expected \"*\" or at least one origin".to_string()),
+            false => Ok(Self::List(list))
+        }
+    }
+}
+
+/*- Comma separated in env -*/
+impl FromStr for AllowedOrigins {
+    type Err = String;
+    fn from_str(value:&str) -> Result<Self, Self::Err> {
+        Self::from_list(value.split(',').map(str::to_string).collect())
+    }
+}
+
+/*- A list of strings in the config file -*/
+impl<'de> serde::Deserialize<'de> for AllowedOrigins {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer:D) -> Result<Self, D::Error> {
+        let origins = Vec::<String>::deserialize(deserializer)?;
+        Self::from_list(origins).map_err(serde::de::Error::custom)
+    }
+}
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f:&mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Invalid configuration ({} problems):", self.0.len())?;
+        for error in &self.0 {
+            writeln!(f, "  - {error}")?;
+        };
+
+        Ok(())
+    }
+}
+
+/*- Tests -*/
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /*- Env is shared by every test in the process, so each
+        test only touches keys no other test reads -*/
+    fn loader() -> Loader {
+        Loader { errors: Vec::new() }
+    }
+
+    #[test]
+    fn env_overrides_the_config_file() {
+        env::set_var("TEST_ENV_OVERRIDE_PORT", "9000");
+        let mut loader = loader();
+
+        assert_eq!(loader.or_default("TEST_ENV_OVERRIDE_PORT", Some(8000u16), 7000), 9000);
+        assert_eq!(loader.or_default("TEST_ENV_OVERRIDE_UNSET", Some(8000u16), 7000), 8000);
+        assert_eq!(loader.or_default::<u16>("TEST_ENV_OVERRIDE_UNSET", None, 7000), 7000);
+        assert!(loader.errors.is_empty());
+    }
+
+    #[test]
+    fn missing_required_value_is_an_error() {
+        let mut loader = loader();
+
+        assert_eq!(loader.required::<String>("TEST_REQUIRED_MISSING", None), None);
+        assert_eq!(loader.errors.len(), 1);
+        assert!(loader.errors[0].starts_with("TEST_REQUIRED_MISSING: missing"));
+    }
+
+    #[test]
+    fn unparsable_env_value_is_an_error() {
+        env::set_var("TEST_UNPARSABLE_BURST", "lots");
+        let mut loader = loader();
+        loader.bucket("TEST_UNPARSABLE", None, Some(2.0), 10.0, 1.0);
+
+        assert_eq!(loader.errors.len(), 1);
+        assert!(loader.errors[0].starts_with("TEST_UNPARSABLE_BURST: invalid value"));
+    }
+
+    #[test]
+    fn invalid_bucket_is_rejected() {
+        let mut errors = Vec::new();
+        BucketConfig { burst: 0.0, per_second: 1.0 }.validate("MESSAGES", &mut errors);
+        BucketConfig { burst: 1.0, per_second: f64::NAN }.validate("ROOM_CREATIONS", &mut errors);
+        BucketConfig { burst: 1.0, per_second: 0.5 }.validate("IP_MESSAGES", &mut errors);
+
+        assert_eq!(errors, vec![
+            "MESSAGES_BURST must be at least 1".to_string(),
+            "ROOM_CREATIONS_PER_SECOND must be above 0".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn unknown_config_file_keys_are_rejected() {
+        assert!(toml::from_str::<ConfigFile>("wss_port = 8080").is_ok());
+        assert!(toml::from_str::<ConfigFile>("wss_prot = 8080").is_err());
+    }
+
+    #[test]
+    fn errors_are_collected_across_fields() {
+        env::set_var("TEST_COLLECT_PORT", "-1");
+        let mut loader = loader();
+        loader.required::<String>("TEST_COLLECT_URL", None);
+        loader.or_default("TEST_COLLECT_PORT", None, 8080u16);
+        let errors = ConfigErrors(loader.errors).to_string();
+
+        assert!(errors.starts_with("Invalid configuration (2 problems):"));
+        assert!(errors.contains("  - TEST_COLLECT_URL: missing"));
+        assert!(errors.contains("  - TEST_COLLECT_PORT: invalid value \"-1\""));
+    }
+}
@@ -6,17 +6,27 @@ use std::{
     sync::Mutex
 };
 use mongodb::{Client, Database};
-use serde_json::{ json, Value };
+use serde_json::{ json, Map, Value };
 use serde_derive::{ Serialize, Deserialize };
+use schemars::JsonSchema;
 use futures_channel::mpsc::{ unbounded, UnboundedSender };
 use futures_util::{ future, pin_mut, stream::TryStreamExt, StreamExt };
-use reqwest;
 use tungstenite::{ WebSocket, Message };
+use tracing::{ debug, field, info, info_span, Instrument, Span };
 use crate::{
-    ACCOUNT_MANAGER_URL,
-    MONGO_DATABASE_NAME,
-    MONGO_HOST,
-    room::Room,
+    room::{ Room, Invite },
+    access,
+    logging::Secret,
+    metrics,
+    rate_limit,
+    config::RoomLimitPolicy,
+    CONFIG,
+    view::{ PublicRoom, Membership },
+    room_store,
+    health::{ self, Dependency },
+    session::{ Session, RateLimits },
+    shutdown,
+    protocol::{ self, Encoding, Frame, Event, ResponseBody, HelloResponse, Limits, RoomCredentialRotated, RoomUnsubscribed },
     player::Player as PlayerInner,
     wrapper::PlayerRedisWrapper as Player,
     ws_status,
@@ -24,220 +34,841 @@ use crate::{
     PeerMap
 };
 
+/*- Constants -*/
+// Longest value accepted for each kind of request field, in bytes
+const MAX_PASSWORD_LENGTH:usize = 128;
+const MAX_JWT_LENGTH:usize = 4096;
+const MAX_ROOM_ID_LENGTH:usize = 16;
+const MAX_SUID_LENGTH:usize = 128;
+const MAX_PRIVATE_ID_LENGTH:usize = 64;
+const MAX_INVITE_LENGTH:usize = 64;
+const MAX_REQUEST_ID_LENGTH:usize = 128;
+const MAX_FEATURES:usize = 32;
+const MAX_FEATURE_LENGTH:usize = 64;
+
+// Metrics label of requests rejected before their destination is known
+const UNKNOWN_DESTINATION:&str = "unknown";
+
 /*- Structs & enums -*/
-#[derive(Serialize, Deserialize, Debug)]
-pub struct GeneralRequest<'a> { pub destination: &'a str, pub data: String }
+/*- Envelope of every request. `data` is normally a JSON object, but
+    older clients send it JSON-encoded as a string which is still accepted -*/
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct GeneralRequest {
+    pub destination: String,
+
+    #[serde(default)]
+    pub data: Value,
+
+    // Optional id picked by the client, echoed back in the response so
+    // it can be matched up with its request
+    #[serde(default)]
+    pub request_id: Option<String>
+}
 
-/*- Main enum for pairing request destinations with their JSON data  -*/
+/*- Main enum for pairing request destinations with their data. Externally
+    tagged so that the same type can be decoded from bincode frames -*/
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
 pub enum RequestJsonType {
     CreateRoom(CreateRoomRequestData),
     JoinRoom(JoinRoomRequestData),
+    LeaveRoom(LeaveRoomRequestData),
+    RoomSettings(RoomSettingsRequestData),
+    TransferLeader(TransferLeaderRequestData),
+    Hello(HelloRequestData),
+    KickPlayer(KickPlayerRequestData),
+    SubscribeRoom(SubscribeRoomRequestData),
+    CreateInvite(CreateInviteRequestData),
+    UnbanPlayer(UnbanPlayerRequestData),
+}
+
+impl GeneralRequest {
+    /*- Deserialize `data` into the struct its destination expects. Errors
+        name the offending field, e.g. "data.room_id: invalid type" -*/
+    pub fn into_request(self) -> Result<RequestJsonType, String> {
+        let data = match self.data {
+            Value::String(encoded) => match serde_json::from_str::<Value>(&encoded) {
+                Ok(e) => e,
+                Err(e) => return Err(format!("data: {e}"))
+            },
+            data => data
+        };
+
+        let mut tagged = Map::new();
+        tagged.insert(self.destination.clone(), data);
+        match serde_path_to_error::deserialize(Value::Object(tagged)) {
+            Ok(request) => Ok(request),
+            Err(e) => {
+                /*- The path starts at the destination, clients know it as `data` -*/
+                let path = e.path().to_string();
+                let path = match path.strip_prefix(self.destination.as_str()) {
+                    Some(rest) if path != "." => format!("data{rest}"),
+                    _ => String::from("destination")
+                };
+                Err(format!("{path}: {}", e.inner()))
+            }
+        }
+    }
+}
+
+impl RequestJsonType {
+    /*- Every destination, in the order of the enum -*/
+    pub const DESTINATIONS:&'static [&'static str] = &[
+        "create-room",
+        "join-room",
+        "leave-room",
+        "room-settings",
+        "transfer-leader",
+        "hello",
+        "kick-player",
+        "subscribe-room",
+        "create-invite",
+        "unban-player",
+    ];
+
+    /*- Name of the destination, as sent by JSON clients -*/
+    pub fn destination(&self) -> &'static str {
+        match self {
+            Self::CreateRoom(_) => "create-room",
+            Self::JoinRoom(_) => "join-room",
+            Self::LeaveRoom(_) => "leave-room",
+            Self::RoomSettings(_) => "room-settings",
+            Self::TransferLeader(_) => "transfer-leader",
+            Self::Hello(_) => "hello",
+            Self::KickPlayer(_) => "kick-player",
+            Self::SubscribeRoom(_) => "subscribe-room",
+            Self::CreateInvite(_) => "create-invite",
+            Self::UnbanPlayer(_) => "unban-player",
+        }
+    }
+
+    /*- Reject oversized fields before they reach a handler, the
+        account manager or mongodb. Errors name the field -*/
+    fn check_lengths(&self) -> Result<(), String> {
+        match self {
+            Self::CreateRoom(data) => limit("jwt", &data.jwt, MAX_JWT_LENGTH),
+            Self::JoinRoom(data) => {
+                limit("jwt", &data.jwt, MAX_JWT_LENGTH)?;
+                limit("room_id", &data.room_id, MAX_ROOM_ID_LENGTH)?;
+                limit("password", data.password.as_deref().unwrap_or_default(), MAX_PASSWORD_LENGTH)?;
+                limit("invite", data.invite.as_deref().unwrap_or_default(), MAX_INVITE_LENGTH)
+            },
+            Self::LeaveRoom(data) => {
+                limit("jwt", &data.jwt, MAX_JWT_LENGTH)?;
+                limit("room_id", &data.room_id, MAX_ROOM_ID_LENGTH)
+            },
+            Self::RoomSettings(data) => {
+                limit("jwt", &data.jwt, MAX_JWT_LENGTH)?;
+                limit("room_id", &data.room_id, MAX_ROOM_ID_LENGTH)?;
+                limit("password", data.password.as_deref().unwrap_or_default(), MAX_PASSWORD_LENGTH)
+            },
+            Self::TransferLeader(data) => {
+                limit("jwt", &data.jwt, MAX_JWT_LENGTH)?;
+                limit("room_id", &data.room_id, MAX_ROOM_ID_LENGTH)?;
+                limit("suid", &data.suid, MAX_SUID_LENGTH)
+            },
+            Self::Hello(data) => {
+                if data.features.len() > MAX_FEATURES { return Err(format!("data.features: more than {MAX_FEATURES} entries")) };
+                data.features.iter().try_for_each(|feature| limit("features", feature, MAX_FEATURE_LENGTH))
+            },
+            Self::KickPlayer(data) => {
+                limit("jwt", &data.jwt, MAX_JWT_LENGTH)?;
+                limit("room_id", &data.room_id, MAX_ROOM_ID_LENGTH)?;
+                limit("suid", &data.suid, MAX_SUID_LENGTH)
+            },
+            Self::SubscribeRoom(data) => {
+                limit("room_id", &data.room_id, MAX_ROOM_ID_LENGTH)?;
+                limit("private_id", &data.private_id, MAX_PRIVATE_ID_LENGTH)
+            },
+            Self::CreateInvite(data) => {
+                limit("jwt", &data.jwt, MAX_JWT_LENGTH)?;
+                limit("room_id", &data.room_id, MAX_ROOM_ID_LENGTH)
+            },
+            Self::UnbanPlayer(data) => {
+                limit("jwt", &data.jwt, MAX_JWT_LENGTH)?;
+                limit("room_id", &data.room_id, MAX_ROOM_ID_LENGTH)?;
+                limit("suid", &data.suid, MAX_SUID_LENGTH)
+            },
+        }
+    }
+
+    /*- External services this request can't be handled without -*/
+    fn dependencies(&self) -> &'static [Dependency] {
+        match self {
+            Self::CreateRoom(_)
+            | Self::JoinRoom(_)
+            | Self::LeaveRoom(_)
+            | Self::RoomSettings(_)
+            | Self::TransferLeader(_) => &[Dependency::AccountManager, Dependency::Mongodb],
+            Self::Hello(_) => &[],
+            Self::KickPlayer(_) => &[Dependency::AccountManager, Dependency::Mongodb],
+            Self::SubscribeRoom(_) => &[Dependency::Mongodb],
+            Self::CreateInvite(_) => &[Dependency::AccountManager, Dependency::Mongodb],
+            Self::UnbanPlayer(_) => &[Dependency::AccountManager, Dependency::Mongodb],
+        }
+    }
 }
 
 /*- Other structs for containing JSON data coupled to requests -*/
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct CreateRoomRequestData {
-    jwt: String,
+    jwt: Secret,
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct JoinRoomRequestData {
-    jwt: String,
+    jwt: Secret,
+    room_id: String,
+
+    // Needed for password protected / invite only rooms
+    #[serde(default)]
+    password: Option<Secret>,
+    #[serde(default)]
+    invite: Option<Secret>
+}
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct LeaveRoomRequestData {
+    jwt: Secret,
     room_id: String
 }
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct RoomSettingsRequestData {
+    jwt: Secret,
+    room_id: String,
+
+    // Settings which are None are left unchanged
+    max_players: Option<u8>,
+    private: Option<bool>,
+
+    // An empty password removes it
+    #[serde(default)]
+    password: Option<Secret>,
+    #[serde(default)]
+    invite_only: Option<bool>
+}
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct TransferLeaderRequestData {
+    jwt: Secret,
+    room_id: String,
+
+    // SUID of the player who will become leader
+    suid: String
+}
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct HelloRequestData {
+    // Newest protocol version the client speaks
+    protocol_version: u32,
+
+    // Optional features the client would like to use
+    #[serde(default)]
+    features: Vec<String>
+}
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct KickPlayerRequestData {
+    jwt: Secret,
+    room_id: String,
+
+    // SUID of the player to remove
+    suid: String,
+
+    // Keep them from rejoining, optionally from their IP as well
+    #[serde(default)]
+    ban: bool,
+    #[serde(default)]
+    ban_ip: bool
+}
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct UnbanPlayerRequestData {
+    jwt: Secret,
+    room_id: String,
+    suid: String
+}
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct CreateInviteRequestData {
+    jwt: Secret,
+    room_id: String,
+
+    // Invites are single use unless this is set
+    #[serde(default)]
+    reusable: bool,
+
+    // Seconds until the invite expires, never if None
+    #[serde(default)]
+    ttl_seconds: Option<u64>
+}
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct SubscribeRoomRequestData {
+    room_id: String,
+
+    // Handed out to players when they create or join the room
+    private_id: Secret
+}
 
 /*- Main -*/
-pub async fn handle_req<'a>(
+pub async fn handle_req(
     msg:tungstenite::Message,
     peer_map: &PeerMap,
-    current_connection: SocketAddr
+    mongodb_connection: &Database,
+    session: &mut Session
 ) -> Result<(), tungstenite::Error> {
-    /*- Get peers -*/
-    let peers = match peer_map.lock() {
+    /*- Get this connection's sender, without holding the peer lock across awaits -*/
+    let websocket = match peer_map.sender(&session.addr) {
+        Some(e) => e,
+        None => return Ok(())
+    };
+    session.touch();
+
+    /*- The first request decides whether this connection speaks JSON or bincode -*/
+    let encoding = match &msg {
+        Message::Text(_) => Encoding::Json,
+        Message::Binary(_) => Encoding::Bincode,
+        _ => return Ok(())
+    };
+
+    /*- Drop messages from connections, IPs & players flooding us. Done before
+        anything is decoded, so malformed frames cost tokens as well -*/
+    let allowed = match RateLimits::take(&mut session.rate_limits.messages) {
+        Ok(_) => rate_limit::take_message(&session.addr.ip(), session.suid()),
+        Err(status) => Err(status)
+    };
+    if let Err(status) = allowed {
+        metrics::count_request(UNKNOWN_DESTINATION, status);
+        session.rate_limits.strike();
+        websocket.unbounded_send(protocol::response(None, Err(status)).encode(session.encoding())).ok();
+        return Ok(())
+    };
+
+    /*- Frames of the other encoding are turned away -*/
+    if !session.negotiate(encoding) {
+        metrics::count_request(UNKNOWN_DESTINATION, ws_status::INVALID_REQUEST);
+        let error = format!("connection already uses {:?} frames", session.encoding());
+        websocket.unbounded_send(protocol::invalid_request(None, error).encode(session.encoding())).ok();
+        return Ok(())
+    };
+    peer_map.set_encoding(&session.addr, encoding);
+
+    /*- Get what type of request this is -*/
+    let (request_id, request):(Option<String>, RequestJsonType) = match decode_request(msg) {
         Ok(e) => e,
-        Err(_) => panic!("")
-    };
-
-    /*- Open database client -*/
-    // let mut mongodb_connection = match mongodb::Client::with_uri_str(&**MONGO_HOST).await {
-	// 	Ok(e) => e,
-	// 	Err(_) => panic!("")
-	// }.database(&**MONGO_DATABASE_NAME);
-
-    /*- Which client we want to broadcast to -*/
-    // let broadcast_recipients = peers
-    //     .iter()
-    //     // .filter(|(peer_addr, _)| peer_addr != &&addr)
-    //     .map(|(_, ws_sink)| ws_sink);
-
-    // /*- Send to all selected client -*/
-    // for recp in broadcast_recipients {
-    //     match recp.unbounded_send(msg.clone()) {
-    //         Ok(e) => e,
-    //         Err(_) => return Ok(())
-    //     };
-    // }
-
-    /*- Get what type of JSON struct to use -*/
-    if let Message::Text(text) = msg {
-        let mut request:RequestJsonType = match serde_json::from_str::<GeneralRequest>(&text) {
-            Ok(GeneralRequest { destination, data }) => {
-                println!("{destination}");
-                /*- Get what type of json data is to be serialized -*/
-                match destination {
-                    "create-room"   => RequestJsonType::CreateRoom(
-                        match serde_json::from_str::<CreateRoomRequestData>(&data) { Ok(e) => e, Err(e) => panic!("{e}") }
-                    ),
-                    "join-room"     => RequestJsonType::JoinRoom(
-                        match serde_json::from_str::<JoinRoomRequestData>(&data) { Ok(e) => e, Err(e) => panic!("{e}") }
-                    ),
-                    _ => panic!("")
-                }
-            },
-            Err(_) => panic!("")
-        };
-        
-        let websocket = peers.get(&current_connection).unwrap();
-
-        /*- Check what request type -*/
-        // match match match request {
-        //     RequestJsonType::CreateRoom(data) => create_room(&data, &mut mongodb_connection).await,
-        //     RequestJsonType::JoinRoom(data) => join_room(&data, &mut mongodb_connection).await,
-        // } {
-
-        //     /*- Write status to websocket tunnel -*/
-        //     Ok(json)    => websocket.unbounded_send(Message::Text(json.to_string())).ok(),
-        //     Err(status) => websocket.unbounded_send(Message::Text(
-        //         json!({
-        //             "status": status
-        //         }).to_string()
-        //     )).ok()
-        // } {
-        //     Some(e) => Ok(e),
-        //     None => panic!("")
-        // }
-        panic!("end")
-    }else {
-        panic!("")
+        Err(frame) => {
+            metrics::count_request(UNKNOWN_DESTINATION, frame.status().unwrap_or(ws_status::INVALID_REQUEST));
+            websocket.unbounded_send(frame.encode(encoding)).ok();
+            return Ok(())
+        }
+    };
+    let request_id = request_id.as_deref();
+    let destination = request.destination();
+    if request_id.is_some_and(|request_id| request_id.len() > MAX_REQUEST_ID_LENGTH) {
+        metrics::count_request(destination, ws_status::PAYLOAD_TOO_LARGE);
+        let error = format!("request_id: longer than {MAX_REQUEST_ID_LENGTH} bytes");
+        websocket.unbounded_send(protocol::rejected(None, ws_status::PAYLOAD_TOO_LARGE, error).encode(encoding)).ok();
+        return Ok(())
+    };
+    if let Err(error) = request.check_lengths() {
+        metrics::count_request(destination, ws_status::PAYLOAD_TOO_LARGE);
+        websocket.unbounded_send(protocol::rejected(request_id, ws_status::PAYLOAD_TOO_LARGE, error).encode(encoding)).ok();
+        return Ok(())
+    };
+
+    /*- Everything logged while handling this carries its destination,
+        and the room it's about once a handler knows it -*/
+    let span = info_span!("request", destination, request_id, room = field::Empty);
+    debug!(parent: &span, ?request, "Handling request");
+
+    /*- Check what request type -*/
+    let result = match health::require(request.dependencies()) {
+        Err(status) => Err(status),
+        Ok(_) => async { match request {
+            RequestJsonType::CreateRoom(data) => create_room(&data, mongodb_connection, peer_map, session).await,
+            RequestJsonType::JoinRoom(data) => join_room(&data, mongodb_connection, peer_map, session).await,
+            RequestJsonType::LeaveRoom(data) => leave_room(&data, mongodb_connection, peer_map, session).await,
+            RequestJsonType::RoomSettings(data) => room_settings(&data, mongodb_connection, peer_map, session).await,
+            RequestJsonType::TransferLeader(data) => transfer_leader(&data, mongodb_connection, peer_map, session).await,
+            RequestJsonType::Hello(data) => hello(&data),
+            RequestJsonType::KickPlayer(data) => kick_player(&data, mongodb_connection, peer_map, session).await,
+            RequestJsonType::SubscribeRoom(data) => subscribe_room(&data, mongodb_connection, peer_map, session).await,
+            RequestJsonType::CreateInvite(data) => create_invite(&data, mongodb_connection, peer_map, session).await,
+            RequestJsonType::UnbanPlayer(data) => unban_player(&data, mongodb_connection, peer_map, session).await,
+        } }.instrument(span.clone()).await
+    };
+    match &result {
+        Ok(_) => debug!(parent: &span, "Request handled"),
+        Err(status) => debug!(parent: &span, status, "Request failed")
+    };
+    metrics::count_request(destination, *result.as_ref().err().unwrap_or(&200));
+
+    /*- Write status to websocket tunnel -*/
+    websocket.unbounded_send(protocol::response(request_id, result).encode(encoding)).ok();
+    Ok(())
+}
+
+/*- Decode a text or binary frame into a request and its id.
+    Err is the frame to reply with if that isn't possible -*/
+fn decode_request(msg:Message) -> Result<(Option<String>, RequestJsonType), Box<Frame>> {
+    match msg {
+        Message::Text(text) => {
+            let general_request = match serde_json::from_str::<GeneralRequest>(&text) {
+                Ok(e) => e,
+                Err(e) => return Err(Box::new(protocol::invalid_request(None, e.to_string())))
+            };
+
+            let request_id = general_request.request_id.clone();
+            match general_request.into_request() {
+                Ok(request) => Ok((request_id, request)),
+                Err(error) => Err(Box::new(protocol::invalid_request(request_id.as_deref(), error)))
+            }
+        },
+        Message::Binary(bytes) => match protocol::decode_binary(&bytes) {
+            Ok(binary_request) => Ok((binary_request.request_id, binary_request.request)),
+            Err(frame) => Err(frame)
+        },
+        _ => Err(Box::new(protocol::invalid_request(None, String::from("expected a text or binary frame"))))
     }
 }
 
 /*- Functions -*/
 pub async fn create_room(
     request:&CreateRoomRequestData,
-    mongodb_connection: &mut Database
-) -> Result<Value, u16> {
-    /*- GET JWT auth status -*/
-    let status:u16 = Player::check_auth(&request.jwt).await;
-
-    /*- Check status -*/
-    match status {
-        // Unauthorized
-        401 => Err(401),
-
-        // Ok
-        200 => {
-            /*- Authorize player -*/
-            let current_player = match req_utils::authorize_player(&request.jwt).await {
-                Ok(player) => player,
-                Err(status) => return Err(status)
+    mongodb_connection: &Database,
+    peer_map: &PeerMap,
+    session: &mut Session
+) -> Result<ResponseBody, u16> {
+    /*- Room creation has its own, stricter limit -*/
+    RateLimits::take(&mut session.rate_limits.room_creations)?;
+
+    /*- Authorize player -*/
+    let current_player = session.authenticate(&request.jwt).await?;
+    rate_limit::take_room_creation(&current_player.player.suid)?;
+
+    /*- Get room details -*/
+    let private_id:String = Room::gen_private_id();
+    let public_id:u32     = room_store::gen_free_public_id(mongodb_connection).await?;
+
+    /*- Only leave another room (per ROOM_LIMIT_POLICY) once the room can be created -*/
+    free_room_slot(mongodb_connection, peer_map, session, &current_player, None).await?;
+
+    let room_name:String  = format!("room:{}", public_id);
+    let mut room          = Room::from_leader(current_player, private_id.clone(), public_id);
+
+    /*- Add room to mongodb, unless another request got the player into a room meanwhile -*/
+    room_store::insert_room(mongodb_connection, &mut room).await?;
+    let public_id = room.public_id;
+    if over_room_limit(mongodb_connection, &room.leader.player.suid).await? {
+        room_store::delete_room(mongodb_connection, &room).await.ok();
+        return Err(ws_status::TOO_MANY_ROOMS)
+    };
+    peer_map.set_suid(&session.addr, &room.leader.player.suid);
+    peer_map.join_room(&session.addr, public_id);
+    session.enter_room(public_id);
+
+    Span::current().record("room", public_id);
+    info!(players = room.players.len(), "Created room");
+
+    /*- Return -*/
+    Ok(ResponseBody::Membership(Membership::of(&room)))
+}
+
+pub async fn join_room(
+    request:&JoinRoomRequestData,
+    mongodb_connection: &Database,
+    peer_map: &PeerMap,
+    session: &mut Session
+) -> Result<ResponseBody, u16> {
+    /*- Authorize player -*/
+    let current_player = session.authenticate(&request.jwt).await?;
+    let public_id = parse_room_id(&request.room_id)?;
+    let suid = current_player.player.suid.as_str();
+    let ip = session.addr.ip().to_string();
+
+    /*- Passwords are checked before the update, hashing takes too long to retry it -*/
+    let mut stored = room_store::get_room(mongodb_connection, public_id).await?;
+    let rejoining = stored.has_player(suid);
+    if !rejoining && stored.is_banned(suid, &ip) { return Err(ws_status::BANNED_FROM_ROOM) };
+    let mut verified_hash:Option<String> = None;
+    if !rejoining && (request.invite.is_some() || stored.invite_only || stored.password_hash.is_some()) {
+        access::take_join_attempt(suid)?;
+
+        if let (None, Some(hash)) = (&request.invite, &stored.password_hash) {
+            match &request.password {
+                Some(password) if access::verify_password(password.to_string(), hash.clone()).await => verified_hash = Some(hash.clone()),
+                _ => return Err(ws_status::WRONG_ROOM_PASSWORD)
+            };
+        };
+    };
+
+    /*- Push player to room, unless they're already in it (e.g. rejoining after reconnect) -*/
+    let mut admit = |room:&mut Room| {
+        if room.has_player(suid) {
+            room.update_player_address(suid, current_player.socket_addr.clone(), current_player.instance.clone());
+            return Ok(())
+        };
+        if room.is_banned(suid, &ip) { return Err(ws_status::BANNED_FROM_ROOM) };
+        match &request.invite {
+            Some(code) => if room.redeem_invite(code).is_err() { return Err(ws_status::INVALID_INVITE) },
+            None => {
+                if room.invite_only { return Err(ws_status::INVITE_REQUIRED) };
+                if room.password_hash.is_some() && room.password_hash != verified_hash { return Err(ws_status::WRONG_ROOM_PASSWORD) };
+            }
+        };
+        match room.add_player(current_player.clone()) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(ws_status::ROOM_FULL)
+        }
+    };
+
+    /*- Only make room for this one (which may leave another room) once the
+        join would succeed on the room as we read it -*/
+    if !rejoining {
+        admit(&mut stored)?;
+        free_room_slot(mongodb_connection, peer_map, session, &current_player, Some(public_id)).await?;
+    };
+    let room = room_store::update_room(mongodb_connection, public_id, &mut admit).await?;
+
+    /*- Another request may have got the player into a room meanwhile -*/
+    if !rejoining && over_room_limit(mongodb_connection, suid).await? {
+        remove_from_room(mongodb_connection, peer_map, session, &current_player, public_id).await.ok();
+        return Err(ws_status::TOO_MANY_ROOMS)
+    };
+
+    peer_map.set_suid(&session.addr, &current_player.player.suid);
+    peer_map.join_room(&session.addr, public_id);
+    session.enter_room(public_id);
+
+    info!(players = room.players.len(), "Joined room");
+
+    /*- Return -*/
+    Ok(ResponseBody::Membership(Membership::of(&room)))
+}
+
+pub async fn leave_room(
+    request:&LeaveRoomRequestData,
+    mongodb_connection: &Database,
+    peer_map: &PeerMap,
+    session: &mut Session
+) -> Result<ResponseBody, u16> {
+    /*- Authorize player -*/
+    let current_player = session.authenticate(&request.jwt).await?;
+    let public_id = parse_room_id(&request.room_id)?;
+
+    remove_from_room(mongodb_connection, peer_map, session, &current_player, public_id).await?;
+
+    /*- Return -*/
+    Ok(ResponseBody::Empty)
+}
+
+/*- Remove player from room, passing on the leader role if needed -*/
+async fn remove_from_room(
+    mongodb_connection: &Database,
+    peer_map: &PeerMap,
+    session: &mut Session,
+    player: &Player,
+    public_id: u32
+) -> Result<(), u16> {
+    room_store::update_room(mongodb_connection, public_id, |room| {
+        match room.remove_player(player.clone()) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(ws_status::PLAYER_NOT_IN_ROOM)
+        }
+    }).await?;
+    for addr in peer_map.addrs_of_suid(&player.player.suid) {
+        peer_map.leave_room(&addr, public_id);
+    };
+    session.exit_room(public_id);
+
+    Ok(())
+}
+
+/*- Make sure the player may enter one more room (`joining` doesn't count),
+    rejecting or leaving their least recently active rooms per ROOM_LIMIT_POLICY -*/
+async fn free_room_slot(
+    mongodb_connection: &Database,
+    peer_map: &PeerMap,
+    session: &mut Session,
+    player: &Player,
+    joining: Option<u32>
+) -> Result<(), u16> {
+    let mut rooms = room_store::rooms_of_player(mongodb_connection, &player.player.suid).await?;
+    rooms.retain(|room| Some(room.public_id) != joining);
+    if rooms.len() < CONFIG.max_rooms_per_player { return Ok(()) };
+    if CONFIG.room_limit_policy == RoomLimitPolicy::Reject { return Err(ws_status::TOO_MANY_ROOMS) };
+
+    rooms.sort_by_key(|room| room.last_activity);
+    let excess = rooms.len() + 1 - CONFIG.max_rooms_per_player;
+    for room in rooms.iter().take(excess) {
+        remove_from_room(mongodb_connection, peer_map, session, player, room.public_id).await?;
+    };
+
+    Ok(())
+}
+
+/*- Checked after entering a room, since free_room_slot can race with
+    requests on other connections or server instances -*/
+async fn over_room_limit(mongodb_connection: &Database, suid:&str) -> Result<bool, u16> {
+    let count = room_store::count_rooms_of_player(mongodb_connection, suid).await?;
+    Ok(count > CONFIG.max_rooms_per_player as u64)
+}
+
+pub async fn room_settings(
+    request:&RoomSettingsRequestData,
+    mongodb_connection: &Database,
+    peer_map: &PeerMap,
+    session: &mut Session
+) -> Result<ResponseBody, u16> {
+    /*- Authorize player -*/
+    let current_player = session.authenticate(&request.jwt).await?;
+    let public_id = parse_room_id(&request.room_id)?;
+    if !session.is_in_room(public_id) { return Err(ws_status::PLAYER_NOT_IN_ROOM) };
+
+    /*- Hash a new password up front, Some(None) removes it -*/
+    let password_hash:Option<Option<String>> = match &request.password {
+        Some(password) if password.is_empty() => Some(None),
+        Some(password) => Some(Some(access::hash_password(password.to_string()).await?)),
+        None => None
+    };
+
+    /*- Only the leader may change settings -*/
+    let room = room_store::update_room(mongodb_connection, public_id, |room| {
+        if !room.is_leader(&current_player.player.suid) { return Err(ws_status::NOT_ROOM_LEADER) };
+
+        if let Some(max_players) = request.max_players {
+            if room.change_max_players(max_players).is_err() { return Err(ws_status::INVALID_ROOM_SETTINGS) };
+        };
+        if let Some(private) = request.private {
+            room.change_room_visibility(private);
+        };
+        if let Some(password_hash) = &password_hash {
+            room.password_hash = password_hash.clone();
+        };
+        if let Some(invite_only) = request.invite_only {
+            room.invite_only = invite_only;
+        };
+
+        Ok(())
+    }).await?;
+
+    /*- Return -*/
+    Ok(ResponseBody::Room(PublicRoom::of(&room)))
+}
+
+pub async fn transfer_leader(
+    request:&TransferLeaderRequestData,
+    mongodb_connection: &Database,
+    peer_map: &PeerMap,
+    session: &mut Session
+) -> Result<ResponseBody, u16> {
+    /*- Authorize player -*/
+    let current_player = session.authenticate(&request.jwt).await?;
+    let public_id = parse_room_id(&request.room_id)?;
+    if !session.is_in_room(public_id) { return Err(ws_status::PLAYER_NOT_IN_ROOM) };
+
+    /*- Only the leader may pass on their role -*/
+    let room = room_store::update_room(mongodb_connection, public_id, |room| {
+        if !room.is_leader(&current_player.player.suid) { return Err(ws_status::NOT_ROOM_LEADER) };
+        match room.transfer_leadership(&request.suid) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(ws_status::PLAYER_NOT_IN_ROOM)
+        }
+    }).await?;
+
+    /*- Return -*/
+    Ok(ResponseBody::Room(PublicRoom::of(&room)))
+}
+
+pub async fn kick_player(
+    request:&KickPlayerRequestData,
+    mongodb_connection: &Database,
+    peer_map: &PeerMap,
+    session: &mut Session
+) -> Result<ResponseBody, u16> {
+    /*- Authorize player -*/
+    let current_player = session.authenticate(&request.jwt).await?;
+    let public_id = parse_room_id(&request.room_id)?;
+    if !session.is_in_room(public_id) { return Err(ws_status::PLAYER_NOT_IN_ROOM) };
+    if current_player.player.suid == request.suid { return Err(ws_status::CANNOT_KICK_SELF) };
+
+    /*- Only the leader may kick -*/
+    let room = room_store::update_room(mongodb_connection, public_id, |room| {
+        if !room.is_leader(&current_player.player.suid) { return Err(ws_status::NOT_ROOM_LEADER) };
+        let kicked = match room.kick_player(&request.suid) {
+            Ok(e) => e,
+            Err(_) => return Err(ws_status::PLAYER_NOT_IN_ROOM)
+        };
+        if request.ban {
+            let ip = match request.ban_ip {
+                true => kicked.socket_addr.parse::<SocketAddr>().ok().map(|addr| addr.ip().to_string()),
+                false => None
             };
+            room.ban(&request.suid, ip);
+        };
 
-            /*- Get room details -*/
-            let private_id:String = Room::gen_private_id();
-            let public_id:u32     = Room::gen_public_id();
-            let room_name:String  = format!("room:{}", public_id);
-            let mut room          = Room::from_leader(current_player, private_id.clone(), public_id);
+        Ok(())
+    }).await?;
+    announce_private_id_rotation(peer_map, &room, &request.suid);
 
-            /*- Add room to mongodb -*/
-            mongodb_connection.collection::<Room>("rooms").insert_one(&room, None).await.unwrap();
+    /*- Return -*/
+    Ok(ResponseBody::Room(PublicRoom::of(&room)))
+}
 
-            /*- Debug -*/
-            room.quick_display("Created room");
+pub async fn create_invite(
+    request:&CreateInviteRequestData,
+    mongodb_connection: &Database,
+    peer_map: &PeerMap,
+    session: &mut Session
+) -> Result<ResponseBody, u16> {
+    /*- Authorize player -*/
+    let current_player = session.authenticate(&request.jwt).await?;
+    let public_id = parse_room_id(&request.room_id)?;
+    if !session.is_in_room(public_id) { return Err(ws_status::PLAYER_NOT_IN_ROOM) };
+
+    let invite = Invite {
+        code: access::gen_invite_code(),
+        single_use: !request.reusable,
+        expires_at: request.ttl_seconds.map(|ttl| Room::unix_now() + ttl)
+    };
 
-            /*- Return -*/
-            Ok(json!({
-                "status": 200,
-                "room": room.to_string()
-            }))
-        },
-        _ => Err(401)
+    /*- Only the leader may invite -*/
+    room_store::update_room(mongodb_connection, public_id, |room| {
+        if !room.is_leader(&current_player.player.suid) { return Err(ws_status::NOT_ROOM_LEADER) };
+        room.add_invite(invite.clone());
+        Ok(())
+    }).await?;
+
+    /*- Return -*/
+    Ok(ResponseBody::Invite(invite))
+}
+
+pub async fn unban_player(
+    request:&UnbanPlayerRequestData,
+    mongodb_connection: &Database,
+    peer_map: &PeerMap,
+    session: &mut Session
+) -> Result<ResponseBody, u16> {
+    /*- Authorize player -*/
+    let current_player = session.authenticate(&request.jwt).await?;
+    let public_id = parse_room_id(&request.room_id)?;
+    if !session.is_in_room(public_id) { return Err(ws_status::PLAYER_NOT_IN_ROOM) };
+
+    /*- Only the leader may lift bans -*/
+    let room = room_store::update_room(mongodb_connection, public_id, |room| {
+        if !room.is_leader(&current_player.player.suid) { return Err(ws_status::NOT_ROOM_LEADER) };
+        room.unban(&request.suid);
+        Ok(())
+    }).await?;
+
+    /*- Return -*/
+    Ok(ResponseBody::Room(PublicRoom::of(&room)))
+}
+
+/*- Listen to a room's channel. Anyone holding the private id may, it's only
+    handed out to admitted players and rotated whenever someone is kicked -*/
+pub async fn subscribe_room(
+    request:&SubscribeRoomRequestData,
+    mongodb_connection: &Database,
+    peer_map: &PeerMap,
+    session: &mut Session
+) -> Result<ResponseBody, u16> {
+    let public_id = parse_room_id(&request.room_id)?;
+    let room = room_store::get_room(mongodb_connection, public_id).await?;
+    if !room.check_private_id(&request.private_id) { return Err(ws_status::INVALID_ROOM_CREDENTIAL) };
+
+    peer_map.join_room(&session.addr, public_id);
+
+    /*- Return -*/
+    Ok(ResponseBody::Room(PublicRoom::of(&room)))
+}
+
+/*- After a kick, players still in the room get the new private id while
+    everyone else on the channel, the kicked player included, is dropped -*/
+fn announce_private_id_rotation(peer_map:&PeerMap, room:&Room, kicked_suid:&str) {
+    for addr in peer_map.room_members(room.public_id) {
+        let suid = peer_map.suid(&addr);
+        let event = match suid.as_deref() {
+            Some(suid) if room.has_player(suid) => Event::RoomCredentialRotated(RoomCredentialRotated {
+                room_id: room.public_id,
+                private_id: room.private_id.clone()
+            }),
+            suid => {
+                peer_map.leave_room(&addr, room.public_id);
+                Event::RoomUnsubscribed(RoomUnsubscribed {
+                    room_id: room.public_id,
+                    reason: match suid == Some(kicked_suid) {
+                        true => String::from("kicked"),
+                        false => String::from("credential-rotated")
+                    }
+                })
+            }
+        };
+        peer_map.send(&addr, protocol::event(event).encode(peer_map.encoding(&addr)));
+    };
+}
+
+/*- Optional handshake. Clients which never send it are treated as
+    speaking protocol version 1 without any optional features -*/
+pub fn hello(
+    request:&HelloRequestData
+) -> Result<ResponseBody, u16> {
+    /*- Speak the newest version both sides know -*/
+    let protocol_version = request.protocol_version.min(protocol::PROTOCOL_VERSION);
+    if protocol_version < protocol::MIN_PROTOCOL_VERSION { return Err(ws_status::UNSUPPORTED_PROTOCOL_VERSION) };
+
+    let features:Vec<String> = request.features
+        .iter()
+        .filter(|feature| protocol::FEATURES.contains(&feature.as_str()))
+        .cloned()
+        .collect();
+
+    /*- Return -*/
+    Ok(ResponseBody::Hello(HelloResponse {
+        protocol_version,
+        features,
+        destinations: RequestJsonType::DESTINATIONS.iter().map(|e| e.to_string()).collect(),
+        limits: Limits::current()
+    }))
+}
+
+/*- Called once a connection is gone, whether it closed, errored or timed out.
+    Lobby seats are freed right away, seats in started games are kept so the
+    player can rejoin, the reaper removes those rooms if everyone is gone -*/
+pub async fn handle_disconnect(
+    peer_map: &PeerMap,
+    mongodb_connection: &Database,
+    session: &Session
+) -> () {
+    let player = match &session.player {
+        Some(e) => e,
+        None => return
+    };
+    if shutdown::is_shutting_down() || !Dependency::Mongodb.is_up() { return };
+
+    for public_id in session.rooms.iter().copied() {
+        room_store::update_room(mongodb_connection, public_id, |room| {
+            if room.started { return Err(ws_status::ROOM_STARTED) };
+
+            /*- The player may have rejoined from another socket already -*/
+            if !room.player_adresses.contains(&session.addr.to_string()) { return Err(ws_status::PLAYER_NOT_IN_ROOM) };
+            match room.remove_player(player.clone()) {
+                Ok(_) => Ok(()),
+                Err(_) => Err(ws_status::PLAYER_NOT_IN_ROOM)
+            }
+        }).await.ok();
+    };
+}
+
+/*- Err naming `field` if `value` is longer than `max` bytes -*/
+fn limit(field:&str, value:&str, max:usize) -> Result<(), String> {
+    match value.len() > max {
+        true => Err(format!("data.{field}: longer than {max} bytes")),
+        false => Ok(())
     }
 }
 
-pub async fn join_room(
-    request:&JoinRoomRequestData,
-    mongodb_connection: &mut Database
-) -> Result<Value, u16> {
-    /*- GET JWT auth status -*/
-    let status:u16 = Player::check_auth(&request.jwt).await;
-    dbg!(1);
-
-    /*- Check status -*/
-    match status {
-        // Unauthorized
-        401 => Err(401),
-
-        // Ok
-        200 => {
-            dbg!(2);
-            /*- Authorize player -*/
-            let current_player = match req_utils::authorize_player(&request.jwt).await {
-                Ok(player) => player,
-                Err(status) => return Err(status)
-            };
-            dbg!(3);
-
-            /*- Get room -*/
-            let room_name:String = format!("room:{}", &request.room_id);
-            // let room_data:BTreeMap<String, String> = match mongodb_connection. (&room_name) {
-            //     Ok(e) => e,
-            //     Err(_) => return Err(ws_status::CORRUPTED_ROOM)
-            // };
-            dbg!(4);
-
-            /*- Get the room but return err if parse failed -*/
-            // let mut room:Room = match Room::from_redis_hash(&room_data) {
-            //     Some(e) => e,
-
-            //     /*- Return -*/
-            //     None => return Err(401)
-            // };
-            dbg!(5);
-
-            /*- Check if player is already in room -*/
-            // if room.players.contains(&current_player) {
-
-            //     Ok(json!({
-            //         "status": 200,
-            //         "room": room.to_string()
-            //     }))
-            // }else {
-
-            //     /*- Push player to room -*/
-            //     room.players.push(current_player);
-
-            //     /*- Convert to redis hash -*/
-            //     room.serialize_players_unchecked();
-
-            //     /*- Create room -*/
-            //     let _:() = match mongodb_connection.hset(&room_name, "players", &room._players) {
-            //         Ok(e) => e,
-            //         Err(_) => return Err(ws_status::ROOM_UPDATE_PLAYERS),
-            //     };
-
-            //     /*- Debug -*/
-            //     room.quick_display("Joined room");
-
-            //     /*- Return -*/
-            // }
-            Ok(json!({
-                "status": 200,
-                "room": "room.to_string()"
-            }))
+/*- Room ids are sent as strings but stored as the numeric public id.
+    Also tags the current request's span with the room -*/
+fn parse_room_id(room_id:&str) -> Result<u32, u16> {
+    match room_id.parse::<u32>() {
+        Ok(e) => {
+            Span::current().record("room", e);
+            Ok(e)
         },
-        _ => Err(401)
+        Err(_) => Err(ws_status::ROOM_NOT_FOUND)
     }
 }
-
-
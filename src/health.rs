@@ -0,0 +1,131 @@
+/*- Imports -*/
+use crate::{ ws_status, CONFIG };
+use mongodb::{ bson::doc, Database };
+use serde_derive::Serialize;
+use tracing::{ info, warn };
+use std::{
+    future::Future,
+    sync::atomic::{ AtomicBool, Ordering },
+    time::Duration,
+};
+
+/*- Constants -*/
+// How often a dependency which is up gets re-checked
+const HEALTHY_CHECK_INTERVAL:Duration = Duration::from_secs(15);
+
+// Retry delays for a dependency which is down, doubling up to the max
+const MIN_RETRY_BACKOFF:Duration = Duration::from_secs(1);
+const MAX_RETRY_BACKOFF:Duration = Duration::from_secs(60);
+
+// A single check may not take longer than this
+const CHECK_TIMEOUT:Duration = Duration::from_secs(5);
+
+/*- Statics -*/
+static MONGODB_UP:AtomicBool = AtomicBool::new(false);
+static ACCOUNT_MANAGER_UP:AtomicBool = AtomicBool::new(false);
+
+/*- Structs, enums & unions -*/
+/*- External services we need for some, but not all, operations -*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dependency {
+    Mongodb,
+    AccountManager,
+}
+
+/*- Snapshot of every dependency, served over HTTP -*/
+#[derive(Serialize, Debug)]
+pub struct HealthReport {
+    pub healthy: bool,
+    pub mongodb: bool,
+    pub account_manager: bool,
+}
+
+/*- Method implementations -*/
+impl Dependency {
+    fn flag(&self) -> &'static AtomicBool {
+        match self {
+            Self::Mongodb => &MONGODB_UP,
+            Self::AccountManager => &ACCOUNT_MANAGER_UP,
+        }
+    }
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Mongodb => "MongoDB",
+            Self::AccountManager => "Account manager",
+        }
+    }
+
+    /*- Status code sent to clients when they need a dependency which is down -*/
+    fn unavailable_status(&self) -> u16 {
+        match self {
+            Self::Mongodb => ws_status::MONGODB_UNAVAILABLE,
+            Self::AccountManager => ws_status::ACCOUNT_MANAGER_UNAVAILABLE,
+        }
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.flag().load(Ordering::Relaxed)
+    }
+}
+
+/*- Functions -*/
+/*- Err with the dependency's status code if it's down -*/
+pub fn require(dependencies:&[Dependency]) -> Result<(), u16> {
+    match dependencies.iter().find(|dependency| !dependency.is_up()) {
+        Some(dependency) => Err(dependency.unavailable_status()),
+        None => Ok(())
+    }
+}
+
+pub fn report() -> HealthReport {
+    let mongodb = Dependency::Mongodb.is_up();
+    let account_manager = Dependency::AccountManager.is_up();
+
+    HealthReport { healthy: mongodb && account_manager, mongodb, account_manager }
+}
+
+/*- Resolves once the dependency has been seen up -*/
+pub async fn wait_until_up(dependency:Dependency) -> () {
+    while !dependency.is_up() {
+        tokio::time::sleep(MIN_RETRY_BACKOFF).await;
+    };
+}
+
+/*- Background tasks checking each dependency forever -*/
+pub async fn monitor_mongodb(mongodb_connection:Database) -> () {
+    monitor(Dependency::Mongodb, || async {
+        mongodb_connection.run_command(doc! { "ping": 1 }, None).await.is_ok()
+    }).await
+}
+pub async fn monitor_account_manager() -> () {
+    monitor(Dependency::AccountManager, || async {
+        reqwest::get(&CONFIG.account_manager_url).await.is_ok()
+    }).await
+}
+
+async fn monitor<F, Fut>(dependency:Dependency, check:F) -> ()
+    where F: Fn() -> Fut, Fut: Future<Output = bool>
+{
+    let mut backoff = MIN_RETRY_BACKOFF;
+
+    loop {
+        let up = tokio::time::timeout(CHECK_TIMEOUT, check()).await.unwrap_or_default();
+
+        /*- Log changes -*/
+        let was_up = dependency.flag().swap(up, Ordering::Relaxed);
+        match (was_up, up) {
+            (false, true) => info!(dependency = dependency.name(), "Dependency is up"),
+            (true, false) => warn!(dependency = dependency.name(), "Dependency is down, retrying in the background"),
+            _ => ()
+        };
+
+        /*- Retry with backoff while down -*/
+        if up {
+            backoff = MIN_RETRY_BACKOFF;
+            tokio::time::sleep(HEALTHY_CHECK_INTERVAL).await;
+        }else {
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+        };
+    }
+}
@@ -0,0 +1,102 @@
+/*- Imports -*/
+use crate::{ access, bans::{ self, ServerBan }, config::AllowedOrigins, health, metrics, schema, ws_status, PeerMap, CONFIG };
+use mongodb::Database;
+use serde_json::json;
+use warp::{ Filter, Rejection, Reply, http::StatusCode };
+use std::{ convert::Infallible, net::SocketAddr };
+
+/*- Functions -*/
+/*- Plain HTTP routes served next to the websocket server -*/
+pub async fn serve(addr:SocketAddr, mongodb_connection:Database, peer_map:PeerMap) -> () {
+    /*- GET /health -*/
+    let health = warp::path("health")
+        .and(warp::get())
+        .map(|| {
+            let report = health::report();
+            let status = if report.healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+
+            warp::reply::with_status(warp::reply::json(&report), status)
+        });
+
+    /*- GET /schema -*/
+    let schema = warp::path("schema")
+        .and(warp::get())
+        .map(|| warp::reply::json(&schema::generate()));
+
+    /*- GET /metrics, in the Prometheus text format -*/
+    let metrics = warp::path("metrics")
+        .and(warp::get())
+        .map({
+            let peer_map = peer_map.clone();
+            move || warp::reply::with_header(metrics::render(&peer_map), "content-type", "text/plain; version=0.0.4")
+        });
+
+    /*- GET, POST & DELETE /admin/bans -*/
+    let with_db = warp::any().map(move || mongodb_connection.clone());
+    let with_peers = warp::any().map(move || peer_map.clone());
+    let bans_path = warp::path!("admin" / "bans").and(admin());
+    let list_bans = bans_path.clone()
+        .and(warp::get())
+        .and(with_db.clone())
+        .and_then(|mongodb_connection:Database| async move {
+            Ok::<_, Infallible>(reply(bans::list(&mongodb_connection).await.map(|bans| json!({ "bans": bans }))))
+        });
+    let add_ban = bans_path.clone()
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_db.clone())
+        .and(with_peers)
+        .and_then(|ban:ServerBan, mongodb_connection:Database, peer_map:PeerMap| async move {
+            Ok::<_, Infallible>(reply(bans::add(&mongodb_connection, &peer_map, ban).await.map(|ban| json!({ "ban": ban }))))
+        });
+    let remove_ban = bans_path
+        .and(warp::delete())
+        .and(warp::body::json())
+        .and(with_db)
+        .and_then(|ban:ServerBan, mongodb_connection:Database| async move {
+            Ok::<_, Infallible>(reply(bans::remove(&mongodb_connection, ban).await.map(|removed| json!({ "removed": removed }))))
+        });
+
+    let routes = health.or(schema).or(metrics).or(list_bans).or(add_ban).or(remove_ban).with(cors());
+    warp::serve(routes).run(addr).await;
+}
+
+/*- Same origins as the websocket handshake, preflights from others are refused -*/
+fn cors() -> warp::cors::Cors {
+    let cors = warp::cors()
+        .allow_methods(["GET", "POST", "DELETE"])
+        .allow_headers(["authorization", "content-type"]);
+
+    match &CONFIG.allowed_origins {
+        AllowedOrigins::Any => cors.allow_any_origin().build(),
+        AllowedOrigins::List(origins) => cors.allow_origins(origins.iter().map(String::as_str)).build()
+    }
+}
+
+/*- Lets requests through if they carry `Authorization: Bearer <ADMIN_TOKEN>`.
+    Without a configured token the admin routes don't exist at all -*/
+fn admin() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(|header:Option<String>| async move {
+            let given = header.as_deref().and_then(|header| header.strip_prefix("Bearer "));
+            match (&CONFIG.admin_token, given) {
+                (Some(token), Some(given)) if access::constant_time_eq(token, given) => Ok(()),
+                _ => Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+/*- JSON reply for an admin route, statuses from ws_status mapped to HTTP -*/
+fn reply(result:Result<serde_json::Value, u16>) -> warp::reply::WithStatus<warp::reply::Json> {
+    match result {
+        Ok(body) => warp::reply::with_status(warp::reply::json(&body), StatusCode::OK),
+        Err(status) => warp::reply::with_status(
+            warp::reply::json(&json!({ "status": status })),
+            match status {
+                ws_status::INVALID_REQUEST => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR
+            }
+        )
+    }
+}
@@ -0,0 +1,130 @@
+/*- Imports -*/
+use crate::{ health::Dependency, room::Room, ws_status };
+use dashmap::DashSet;
+use futures_util::TryStreamExt;
+use lazy_static::lazy_static;
+use mongodb::{ bson::doc, options::UpdateOptions, Collection, Database };
+use serde_derive::{ Serialize, Deserialize };
+use tracing::warn;
+use uuid::Uuid;
+use std::{
+    sync::atomic::{ AtomicBool, AtomicU64, Ordering },
+    time::Duration,
+};
+
+/*- Several server instances share the rooms collection. Each one
+    heartbeats into mongodb, so that an instance only ever cleans up
+    rooms it owns itself, or rooms whose owner has stopped heartbeating. -*/
+
+/*- Constants -*/
+const INSTANCES_COLLECTION:&str = "instances";
+const HEARTBEAT_INTERVAL:Duration = Duration::from_secs(10);
+
+// Instances which haven't heartbeated for this long are considered dead,
+// and their heartbeat is deleted once it's this many timeouts old
+const INSTANCE_TIMEOUT_SECONDS:u64 = 60;
+const FORGET_AFTER_TIMEOUTS:u64 = 10;
+
+/*- Statics -*/
+// Unix timestamp (seconds) of our last successful heartbeat
+static LAST_HEARTBEAT:AtomicU64 = AtomicU64::new(0);
+
+// Set on graceful shutdown, after which we no longer heartbeat
+static STOPPED:AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    // Random per process, so a restarted instance is a new one
+    static ref INSTANCE_ID: String = Uuid::new_v4().to_string();
+
+    // Other instances which heartbeated recently, as of our last heartbeat
+    static ref LIVE_INSTANCES: DashSet<String> = DashSet::new();
+}
+
+/*- Structs, enums & unions -*/
+#[derive(Serialize, Deserialize, Debug)]
+struct Instance {
+    #[serde(rename = "_id")]
+    id: String,
+
+    // Unix timestamp (seconds)
+    last_heartbeat: u64,
+}
+
+/*- Functions -*/
+fn instances(mongodb_connection:&Database) -> Collection<Instance> {
+    mongodb_connection.collection::<Instance>(INSTANCES_COLLECTION)
+}
+
+/*- Id of this instance, stored as the owner of the rooms it creates -*/
+pub fn id() -> &'static str {
+    INSTANCE_ID.as_str()
+}
+
+/*- Whether `instance` heartbeated recently. Rooms created before
+    owners were stored have an empty owner, which is never alive -*/
+pub fn is_alive(instance:&str) -> bool {
+    instance == id() || LIVE_INSTANCES.contains(instance)
+}
+
+/*- Whether we know which instances are alive. Until then, or while
+    heartbeats fail, other instances' rooms must be left alone -*/
+pub fn is_current() -> bool {
+    Room::unix_now().saturating_sub(LAST_HEARTBEAT.load(Ordering::Relaxed)) < INSTANCE_TIMEOUT_SECONDS
+}
+
+/*- Every instance which is alive, this one included -*/
+pub fn live_instances() -> Vec<String> {
+    let mut live:Vec<String> = LIVE_INSTANCES.iter().map(|instance| instance.clone()).collect();
+    live.push(id().to_string());
+    live
+}
+
+/*- Heartbeat every HEARTBEAT_INTERVAL -*/
+pub async fn run(mongodb_connection:Database) -> () {
+    let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+    loop {
+        interval.tick().await;
+        if STOPPED.load(Ordering::Relaxed) { return };
+        if !Dependency::Mongodb.is_up() { continue };
+
+        if let Err(status) = heartbeat(&mongodb_connection).await {
+            warn!(status, "Could not heartbeat");
+        };
+    };
+}
+
+/*- Remove our heartbeat, so other instances treat us as stopped -*/
+pub async fn stop(mongodb_connection:&Database) -> () {
+    STOPPED.store(true, Ordering::Relaxed);
+    instances(mongodb_connection).delete_one(doc! { "_id": id() }, None).await.ok();
+}
+
+/*- Store our heartbeat, then reload which other instances are alive -*/
+pub async fn heartbeat(mongodb_connection:&Database) -> Result<(), u16> {
+    let now = Room::unix_now();
+    let upsert = UpdateOptions::builder().upsert(true).build();
+    let update = doc! { "$set": { "last_heartbeat": now as i64 } };
+    if instances(mongodb_connection).update_one(doc! { "_id": id() }, update, upsert).await.is_err() {
+        return Err(ws_status::DATABASE_ERROR)
+    };
+
+    let filter = doc! { "last_heartbeat": { "$gte": now.saturating_sub(INSTANCE_TIMEOUT_SECONDS) as i64 } };
+    let live:Vec<Instance> = match instances(mongodb_connection).find(filter, None).await {
+        Ok(cursor) => match cursor.try_collect().await {
+            Ok(e) => e,
+            Err(_) => return Err(ws_status::DATABASE_ERROR)
+        },
+        Err(_) => return Err(ws_status::DATABASE_ERROR)
+    };
+
+    /*- Every restart is a new instance, don't keep their heartbeats forever -*/
+    let forget = doc! { "last_heartbeat": { "$lt": now.saturating_sub(INSTANCE_TIMEOUT_SECONDS * FORGET_AFTER_TIMEOUTS) as i64 } };
+    instances(mongodb_connection).delete_many(forget, None).await.ok();
+
+    /*- Add before removing, so no live instance is missing in between -*/
+    for instance in &live { LIVE_INSTANCES.insert(instance.id.clone()); };
+    LIVE_INSTANCES.retain(|instance| instance != id() && live.iter().any(|live| &live.id == instance));
+    LAST_HEARTBEAT.store(now, Ordering::Relaxed);
+
+    Ok(())
+}
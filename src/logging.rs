@@ -0,0 +1,56 @@
+/*- Imports -*/
+use crate::{ config::LogFormat, CONFIG };
+use schemars::JsonSchema;
+use serde_derive::{ Serialize, Deserialize };
+use tracing_subscriber::EnvFilter;
+use std::{ fmt, ops::Deref };
+
+/*- Leveled, structured logs. Connections, requests & rooms each get a
+    span, so every event carries the address, SUID, destination and room
+    it happened for. Player data & credentials are never logged. -*/
+
+/*- Structs, enums & unions -*/
+/*- Credentials inside requests. Encoded exactly like a plain string,
+    but never printed, so requests can safely be logged with `?request` -*/
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+/*- Method implementations -*/
+impl Deref for Secret {
+    type Target = str;
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f:&mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[redacted, {} bytes]", self.0.len())
+    }
+}
+
+/*- Functions -*/
+/*- Install the global subscriber. LOG_LEVEL takes filter directives,
+    e.g. "info" or "info,scrapbox_backend_websocket::handle_req=debug" -*/
+pub fn init() {
+    let (filter, invalid) = match EnvFilter::try_new(&CONFIG.log_level) {
+        Ok(filter) => (filter, None),
+        Err(e) => (EnvFilter::new("info"), Some(e))
+    };
+
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match CONFIG.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(true)
+            .init()
+    };
+
+    if let Some(e) = invalid {
+        tracing::warn!(log_level = %CONFIG.log_level, error = %e, "Invalid LOG_LEVEL, logging at info");
+    };
+}
@@ -15,113 +15,289 @@ mod player;
 mod wrapper;
 
 mod room;
+mod config;
 mod handle_req;
 mod ws_status;
 mod req_utils;
+mod room_store;
+mod reaper;
+mod shutdown;
+mod tls;
+mod health;
+mod http;
+mod registry;
+mod session;
+mod protocol;
+mod schema;
+mod view;
+mod access;
+mod bans;
+mod rate_limit;
+mod logging;
+mod metrics;
+mod instance;
 // ---
-use tokio_tungstenite;
-use tungstenite::protocol::Message;
-use tokio::net::{ self, TcpListener, TcpStream };
+use tungstenite::{
+	protocol::{ Message, CloseFrame, WebSocketConfig, frame::coding::CloseCode },
+	handshake::server::{ Request, Response, ErrorResponse },
+	http::{ header::ORIGIN, StatusCode },
+};
+use tokio::{
+	net::{ self, TcpListener, TcpStream },
+	io::{ AsyncRead, AsyncWrite },
+};
+use tokio_native_tls::TlsAcceptor;
 use serde_json::{ json, Value };
 use player::Player;
 use room::Room;
+use config::Config;
+use registry::ConnectionRegistry;
+use session::Session;
 use lazy_static::lazy_static;
-use mongodb;
+use mongodb::{ self, Database };
 use dotenv::dotenv;
+use tracing::{ error, field, info, info_span, warn, Instrument };
 use handle_req::handle_req;
-use responder::prelude::*;
 use futures_channel::mpsc::{ unbounded, UnboundedSender };
-use futures_util::{ future, pin_mut, stream::TryStreamExt, StreamExt };
+use futures_util::{ future::{ self, Either }, pin_mut, stream::TryStreamExt, StreamExt };
 use std::{
 	env,
 	thread,
 	sync::{ Mutex, Arc },
 	collections::HashMap,
 	net::SocketAddr,
+	time::Duration,
 };
 
 use crate::handle_req::{RequestJsonType, GeneralRequest, CreateRoomRequestData, JoinRoomRequestData};
 
 /*- Constants -*/
-const WSS_ADDRESS :&str = "127.0.0.1";
-const WSS_PORT    :u16  = 8080;
-const ENV_MONGO_HOST: &'static str = "MONGO_HOST_URL";
-const ENV_ACCOUNT_MANAGER_URL: &'static str = "ACCOUNT_MANAGER_URL";
-const ENV_MONGO_DATABASE_NAME: &'static str = "MONGO_DATABASE_NAME";
+// How long a closing connection gets to flush its last frames
+const CLOSE_FLUSH_TIMEOUT:Duration = Duration::from_secs(1);
+
+// Peers which haven't finished the TLS handshake by then are dropped
+const TLS_HANDSHAKE_TIMEOUT:Duration = Duration::from_secs(10);
 
 /*- Types -*/
-type Tx = UnboundedSender<Message>;
-pub type PeerMap = Arc<Mutex<HashMap<SocketAddr, Tx>>>;
+pub type PeerMap = Arc<ConnectionRegistry>;
 
 /*- Lazy statics -*/
 lazy_static! {
-	static ref MONGO_HOST: String          = env::var(ENV_MONGO_HOST).unwrap();
-	static ref MONGO_DATABASE_NAME: String = env::var(ENV_MONGO_DATABASE_NAME).unwrap();
-
-	/*- Account manager -*/
-	static ref ACCOUNT_MANAGER_URL: String = env::var(ENV_ACCOUNT_MANAGER_URL).unwrap();
+	/*- Validated configuration, see config.rs -*/
+	static ref CONFIG: Config = Config::load_or_exit();
 }
 
 /*- Initialize -*/
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
-	/*- Initialize .env k&v:s -*/
-	dotenv().unwrap();
-
-	/*- Pre-warn about missing ENV variables, because lazy static won't initialize them until read -*/
-	env::var(ENV_MONGO_HOST).expect("MONGO_HOST env var missing");
-	env::var(ENV_ACCOUNT_MANAGER_URL).expect("ACCOUNT_MANAGER_URL env var missing");
-
-	/*- Pre-warn about mongodb connection -*/
-	mongodb::Client::with_uri_str(&**MONGO_HOST).await
-		.expect("MongoDB not up!")
-		.database(&**MONGO_DATABASE_NAME);
-
-	/*- Pre-warn about scrapbox-account-manager connection -*/
-	reqwest::get(&**ACCOUNT_MANAGER_URL).await
-		.expect("Account manager server not up!");
+	/*- Initialize .env k&v:s, the file is optional -*/
+	dotenv().ok();
+
+	/*- Load config now, because lazy static won't initialize it until read -*/
+	lazy_static::initialize(&CONFIG);
+	logging::init();
+
+	/*- Create the mongodb client, which pools connections and is shared by
+		every request. This doesn't connect until first used -*/
+	let mut mongodb_options = mongodb::options::ClientOptions::parse(&CONFIG.mongo_host_url).await
+		.expect("Invalid MONGO_HOST_URL");
+	mongodb_options.command_event_handler = Some(Arc::new(metrics::MongodbTimer));
+	let mongodb_connection = mongodb::Client::with_options(mongodb_options)
+		.expect("Invalid MONGO_HOST_URL")
+		.database(&CONFIG.mongo_database_name);
+
+	/*- Keep checking our dependencies in the background, operations
+		needing one which is down are rejected until it comes back -*/
+	tokio::spawn(health::monitor_mongodb(mongodb_connection.clone()));
+	tokio::spawn(health::monitor_account_manager());
+	tokio::spawn(bans::run(mongodb_connection.clone()));
+	tokio::spawn(rate_limit::run());
 
 	/*- Start websocket server listener -*/
-	let server = TcpListener::bind(format!("{}:{}", WSS_ADDRESS, WSS_PORT)).await.unwrap();
+	let server = TcpListener::bind(format!("{}:{}", CONFIG.wss_address, CONFIG.wss_port)).await.unwrap();
 
-	/*- Print the launch -*/
-	println!("Launch successful on {}:{}!", WSS_ADDRESS, WSS_PORT);
+	/*- Terminate TLS ourselves if a certificate is configured -*/
+	let tls_acceptor = match tls::load_acceptor() {
+		Ok(e) => e,
+		Err(e) => {
+			error!(error = %e, "Could not set up TLS");
+			std::process::exit(1);
+		}
+	};
 
-	/*- Create websocket client hashmap -*/
-	let peers:PeerMap = Arc::new(Mutex::new(HashMap::new()));
+	/*- Log the launch -*/
+	info!(
+		scheme = if tls_acceptor.is_some() { "wss" } else { "ws" },
+		address = %CONFIG.wss_address,
+		port = CONFIG.wss_port,
+		"Launch successful"
+	);
 
-    /*- Get every request isn't Err(_) -*/
-	while let Ok((stream, addr)) = server.accept().await {
-		tokio::spawn(handle_ws_connection(peers.clone(), stream, addr));
+	/*- Create websocket client hashmap -*/
+	let peers:PeerMap = Arc::new(ConnectionRegistry::new());
+
+	/*- Once mongodb is reachable, remove rooms left over from a previous
+		run and start removing abandoned rooms in the background -*/
+	tokio::spawn({
+		let mongodb_connection = mongodb_connection.clone();
+		let peers = peers.clone();
+		async move {
+			health::wait_until_up(health::Dependency::Mongodb).await;
+			room_store::create_indexes(&mongodb_connection).await;
+			if instance::heartbeat(&mongodb_connection).await.is_ok() {
+				reaper::clear_leftover_rooms(&mongodb_connection).await;
+			};
+			tokio::spawn(instance::run(mongodb_connection.clone()));
+			reaper::run(mongodb_connection, peers).await;
+		}
+	});
+
+	/*- Serve HTTP routes -*/
+	tokio::spawn(http::serve(SocketAddr::new(
+		CONFIG.wss_address.parse().expect("WSS_ADDRESS must be an IP address"),
+		CONFIG.http_port
+	), mongodb_connection.clone(), peers.clone()));
+
+	/*- Get every request isn't Err(_), until we're told to shut down -*/
+	let shutdown_signal = shutdown::signal();
+	pin_mut!(shutdown_signal);
+	loop {
+		tokio::select! {
+			accepted = server.accept() => match accepted {
+				/*- Banned addresses are dropped before any handshake, and
+					everyone is until we know who is banned -*/
+				Ok((stream, _)) if !bans::is_loaded() => drop(stream),
+				Ok((stream, addr)) if bans::is_ip_banned(&addr.ip()) => drop(stream),
+				Ok((stream, addr)) if !rate_limit::allow_connection(&addr.ip()) => drop(stream),
+				Ok((stream, addr)) => {
+					/*- Everything logged for this connection carries its address, and SUID once authenticated -*/
+					let span = info_span!("connection", %addr, suid = field::Empty);
+					match tls_acceptor.clone() {
+						Some(acceptor) => { tokio::spawn(handle_tls_connection(acceptor, peers.clone(), mongodb_connection.clone(), stream, addr).instrument(span)); },
+						None => { tokio::spawn(handle_ws_connection(peers.clone(), mongodb_connection.clone(), stream, addr).instrument(span)); }
+					};
+				},
+				Err(_) => break
+			},
+			_ = &mut shutdown_signal => break
+		};
 	};
+
+	/*- Stop accepting connections & drain the ones we have -*/
+	drop(server);
+	info!("Shutting down, draining connections");
+	shutdown::drain(&mongodb_connection, &peers, CONFIG.shutdown_drain_period).await;
+
 	Ok(())
 }
 
 
-async fn handle_ws_connection(peer_map: PeerMap, raw_stream: TcpStream, addr: SocketAddr) {
-	/*- Try accept websocket tunnel connection -*/
-	let stream = match tokio_tungstenite::accept_async(raw_stream).await {
+/*- Finish the TLS handshake before upgrading to websocket, within TLS_HANDSHAKE_TIMEOUT -*/
+async fn handle_tls_connection(acceptor: TlsAcceptor, peer_map: PeerMap, mongodb_connection: Database, raw_stream: TcpStream, addr: SocketAddr) {
+	if let Ok(Ok(tls_stream)) = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(raw_stream)).await {
+		handle_ws_connection(peer_map, mongodb_connection, tls_stream, addr).await
+	};
+}
+
+async fn handle_ws_connection<S>(peer_map: PeerMap, mongodb_connection: Database, raw_stream: S, addr: SocketAddr)
+	where S: AsyncRead + AsyncWrite + Unpin
+{
+	/*- Try accept websocket tunnel connection, oversized messages are refused while reading -*/
+	let config = WebSocketConfig {
+		max_message_size: Some(CONFIG.max_message_size),
+		max_frame_size: Some(CONFIG.max_frame_size),
+		..WebSocketConfig::default()
+	};
+	#[allow(clippy::result_large_err)]
+	let check_origin = |request:&Request, response:Response| check_origin(request, response, addr);
+	let stream = match tokio_tungstenite::accept_hdr_async_with_config(raw_stream, check_origin, Some(config)).await {
 		Ok(e) => e,
 		Err(_) => return
 	};
+	info!("Connected");
 
 	/*- Push client -*/
     let (tx, rx) = unbounded();
-    peer_map.lock().unwrap().insert(addr, tx);
-	let (outgoing, incoming) = stream.split();
-
-	/*- Get incoming requests -*/
-    let broadcast_incoming = incoming.try_for_each(|message| handle_req(message, &peer_map, addr));
+    peer_map.insert(addr, tx);
+	let (outgoing, mut incoming) = stream.split();
+
+	/*- Get incoming requests, one at a time so they can share the session.
+		Between messages we ping the client, and drop it if it stops
+		answering pings or goes quiet for too long -*/
+	let mut session = Session::new(addr);
+	let mut heartbeat = tokio::time::interval(CONFIG.heartbeat_interval);
+	let broadcast_incoming = async {
+		loop {
+			tokio::select! {
+				message = incoming.next() => match message {
+					Some(Ok(Message::Pong(_))) => session.pong(),
+					Some(Ok(Message::Ping(_))) => (),
+					Some(Err(tungstenite::Error::Capacity(_))) => {
+						peer_map.send(&addr, Message::Close(Some(CloseFrame { code: CloseCode::Size, reason: "message too big".into() })));
+						break
+					},
+					Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+					Some(Ok(_)) if session.rate_limits.is_abusive() => break,
+					Some(Ok(message)) => {
+						if handle_req(message, &peer_map, &mongodb_connection, &mut session).await.is_err() { break };
+
+						/*- Ask abusive clients to leave & keep them out for a while -*/
+						if session.rate_limits.is_abusive() {
+							rate_limit::block(&addr.ip());
+							peer_map.send(&addr, Message::Close(Some(CloseFrame { code: CloseCode::Policy, reason: "rate limited".into() })));
+						};
+					},
+				},
+				_ = heartbeat.tick() => {
+					if session.rate_limits.is_abusive() { break };
+					if session.since_pong() > CONFIG.pong_timeout || session.idle_for() > CONFIG.idle_timeout { break };
+					peer_map.send(&addr, Message::Ping(Vec::new()));
+				}
+			};
+		};
+	};
 
     let receive_from_others = rx.map(Ok).forward(outgoing);
+	pin_mut!(receive_from_others);
+
+	let forwarding = {
+		pin_mut!(broadcast_incoming);
+		match future::select(broadcast_incoming, receive_from_others.as_mut()).await {
+			Either::Left(_) => true,
+			Either::Right(_) => false
+		}
+	};
 
-    pin_mut!(broadcast_incoming, receive_from_others);
-    future::select(broadcast_incoming, receive_from_others).await;
+    info!("Disconnected");
 
-    println!("{} disconnected", &addr);
+	/*- Free the player's seats, the same way for timeouts & regular disconnects -*/
+	handle_req::handle_disconnect(&peer_map, &mongodb_connection, &session).await;
 
 	/*- Remove connection from peer map -*/
-    peer_map.lock().unwrap().remove(&addr);
+    peer_map.remove(&addr);
+
+	/*- That closed the channel, so the forwarder ends once it has
+		flushed whatever is still queued, e.g. a close frame -*/
+	if forwarding {
+		tokio::time::timeout(CLOSE_FLUSH_TIMEOUT, receive_from_others).await.ok();
+	};
+}
+
+/*- Refuse the handshake from browsers on origins not in ALLOWED_ORIGINS,
+	so other websites can't act for a player whose JWT they got hold of.
+	The error type is what tungstenite's handshake callback returns -*/
+#[allow(clippy::result_large_err)]
+fn check_origin(request:&Request, response:Response, addr:SocketAddr) -> Result<Response, ErrorResponse> {
+	let origin = match request.headers().get(ORIGIN) {
+		Some(origin) => origin.to_str().unwrap_or_default(),
+		None => return Ok(response)
+	};
+	if CONFIG.allowed_origins.allows(origin) { return Ok(response) };
+
+	warn!(origin, "Rejected websocket, origin is not allowed");
+	let mut rejection = ErrorResponse::new(Some("Origin not allowed".to_string()));
+	*rejection.status_mut() = StatusCode::FORBIDDEN;
+	Err(rejection)
 }
 
 
@@ -0,0 +1,191 @@
+/*- Imports -*/
+use crate::{ health::Dependency, room::Room, PeerMap };
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use mongodb::event::command::{ CommandEventHandler, CommandFailedEvent, CommandSucceededEvent };
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    future::Future,
+    sync::Mutex,
+    time::{ Duration, Instant },
+};
+
+/*- Prometheus metrics, rendered in the text exposition format on
+    GET /metrics. Connected sockets & messages pushed are read from the
+    registry when scraped, everything else is recorded as it happens.
+    Every metric is about this instance only, so they can be summed
+    across instances without counting anything twice. -*/
+
+/*- Constants -*/
+// Upper bounds of histogram buckets, +Inf is implied
+const LATENCY_BUCKETS:&[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/*- Statics -*/
+lazy_static! {
+    // (destination, status) -> requests answered
+    static ref REQUESTS: DashMap<(&'static str, u16), u64> = DashMap::new();
+
+    static ref MONGODB_LATENCY: Mutex<Histogram> = Mutex::new(Histogram::new(LATENCY_BUCKETS));
+    static ref ACCOUNT_MANAGER_LATENCY: Mutex<Histogram> = Mutex::new(Histogram::new(LATENCY_BUCKETS));
+
+    // Rooms this instance owns, as of the reaper's last sweep
+    static ref ROOMS: Mutex<RoomsSnapshot> = Mutex::new(RoomsSnapshot::of(&[]));
+}
+
+/*- Structs, enums & unions -*/
+/*- Bucket counts are cumulative, like they're exposed -*/
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+struct RoomsSnapshot {
+    rooms: usize,
+    started: usize,
+
+    // Player count -> rooms with that many players
+    by_players: BTreeMap<usize, usize>,
+}
+
+/*- Times every command the mongodb client runs, registered on its options -*/
+pub struct MongodbTimer;
+
+/*- Method implementations -*/
+impl Histogram {
+    fn new(bounds:&'static [f64]) -> Self {
+        Self { bounds, counts: vec![0; bounds.len()], sum: 0.0, count: 0 }
+    }
+
+    fn observe(&mut self, value:f64) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound { *count += 1 };
+        };
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /*- `labels` are put in front of `le`, e.g. `dependency="mongodb",` -*/
+    fn render(&self, out:&mut String, name:&str, labels:&str) {
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            writeln!(out, "{name}_bucket{{{labels}le=\"{bound}\"}} {count}").ok();
+        };
+        writeln!(out, "{name}_bucket{{{labels}le=\"+Inf\"}} {}", self.count).ok();
+
+        let labels = match labels.trim_end_matches(',') {
+            "" => String::new(),
+            labels => format!("{{{labels}}}")
+        };
+        writeln!(out, "{name}_sum{labels} {}", self.sum).ok();
+        writeln!(out, "{name}_count{labels} {}", self.count).ok();
+    }
+}
+
+impl RoomsSnapshot {
+    fn of(rooms:&[Room]) -> Self {
+        let owned:Vec<&Room> = rooms.iter().filter(|room| room.is_owned_here()).collect();
+        let mut by_players = BTreeMap::new();
+        for room in &owned {
+            *by_players.entry(room.players.len()).or_insert(0) += 1;
+        };
+
+        Self {
+            rooms: owned.len(),
+            started: owned.iter().filter(|room| room.started).count(),
+            by_players
+        }
+    }
+}
+
+impl CommandEventHandler for MongodbTimer {
+    fn handle_command_succeeded_event(&self, event:CommandSucceededEvent) {
+        observe_latency(Dependency::Mongodb, event.duration);
+    }
+    fn handle_command_failed_event(&self, event:CommandFailedEvent) {
+        observe_latency(Dependency::Mongodb, event.duration);
+    }
+}
+
+/*- Functions -*/
+pub fn count_request(destination:&'static str, status:u16) {
+    *REQUESTS.entry((destination, status)).or_insert(0) += 1;
+}
+
+/*- Await `future`, recording how long it took as a call to `dependency` -*/
+pub async fn timed<T>(dependency:Dependency, future:impl Future<Output = T>) -> T {
+    let start = Instant::now();
+    let output = future.await;
+    observe_latency(dependency, start.elapsed());
+
+    output
+}
+
+fn latency(dependency:Dependency) -> &'static Mutex<Histogram> {
+    match dependency {
+        Dependency::Mongodb => &MONGODB_LATENCY,
+        Dependency::AccountManager => &ACCOUNT_MANAGER_LATENCY,
+    }
+}
+fn observe_latency(dependency:Dependency, duration:Duration) {
+    if let Ok(mut histogram) = latency(dependency).lock() {
+        histogram.observe(duration.as_secs_f64());
+    };
+}
+
+/*- Called by the reaper with the rooms it swept, only those owned here are counted -*/
+pub fn record_rooms(rooms:&[Room]) {
+    if let Ok(mut snapshot) = ROOMS.lock() {
+        *snapshot = RoomsSnapshot::of(rooms);
+    };
+}
+
+/*- Every metric in the Prometheus text format -*/
+pub fn render(peer_map:&PeerMap) -> String {
+    let mut out = String::new();
+
+    writeln!(out, "# HELP scrapbox_connected_sockets Open websocket connections to this instance").ok();
+    writeln!(out, "# TYPE scrapbox_connected_sockets gauge").ok();
+    writeln!(out, "scrapbox_connected_sockets {}", peer_map.len()).ok();
+
+    writeln!(out, "# HELP scrapbox_messages_pushed_total Messages pushed to connections (broadcasts & events), responses excluded").ok();
+    writeln!(out, "# TYPE scrapbox_messages_pushed_total counter").ok();
+    writeln!(out, "scrapbox_messages_pushed_total {}", peer_map.messages_pushed()).ok();
+
+    /*- Room metrics are per-instance snapshots replaced on every sweep, so the
+        players-per-room distribution is a gauge per player count rather than a
+        histogram, whose buckets would have to only ever grow. Sum by `players`
+        across instances for the whole deployment -*/
+    if let Ok(snapshot) = ROOMS.lock() {
+        writeln!(out, "# HELP scrapbox_rooms Rooms owned by this instance, as of its last reaper sweep").ok();
+        writeln!(out, "# TYPE scrapbox_rooms gauge").ok();
+        writeln!(out, "scrapbox_rooms {}", snapshot.rooms).ok();
+        writeln!(out, "# HELP scrapbox_started_rooms Rooms owned by this instance whose game has started, as of its last reaper sweep").ok();
+        writeln!(out, "# TYPE scrapbox_started_rooms gauge").ok();
+        writeln!(out, "scrapbox_started_rooms {}", snapshot.started).ok();
+        writeln!(out, "# HELP scrapbox_rooms_by_players Players-per-room distribution: rooms owned by this instance with `players` players. Per-instance snapshot taken at its last reaper sweep").ok();
+        writeln!(out, "# TYPE scrapbox_rooms_by_players gauge").ok();
+        for (players, rooms) in &snapshot.by_players {
+            writeln!(out, "scrapbox_rooms_by_players{{players=\"{players}\"}} {rooms}").ok();
+        };
+    };
+
+    let mut requests:Vec<((&str, u16), u64)> = REQUESTS.iter().map(|entry| (*entry.key(), *entry.value())).collect();
+    requests.sort();
+    writeln!(out, "# HELP scrapbox_requests_total Requests answered, by destination & status").ok();
+    writeln!(out, "# TYPE scrapbox_requests_total counter").ok();
+    for ((destination, status), count) in requests {
+        writeln!(out, "scrapbox_requests_total{{destination=\"{destination}\",status=\"{status}\"}} {count}").ok();
+    };
+
+    writeln!(out, "# HELP scrapbox_dependency_latency_seconds Time spent on calls to mongodb & the account manager").ok();
+    writeln!(out, "# TYPE scrapbox_dependency_latency_seconds histogram").ok();
+    for (label, dependency) in [("mongodb", Dependency::Mongodb), ("account_manager", Dependency::AccountManager)] {
+        if let Ok(histogram) = latency(dependency).lock() {
+            histogram.render(&mut out, "scrapbox_dependency_latency_seconds", &format!("dependency=\"{label}\","));
+        };
+    };
+
+    out
+}
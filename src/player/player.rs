@@ -5,11 +5,11 @@
 
 /*- Imports -*/
 use serde_derive::{ Serialize, Deserialize };
+use schemars::JsonSchema;
 use std::default::Default;
-use crate::ACCOUNT_MANAGER_URL;
 
 /*- Structs, enums & unions -*/
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Player {
     pub suid: String,
 
@@ -25,7 +25,7 @@ pub struct Player {
 }
 
 /*- Game statistics -*/
-#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default)]
 pub struct GameStatistics {
     pub games_won    : u32,
     pub games_played : u32,
@@ -47,26 +47,11 @@ impl Player {
     }
 }
 
-/*- Default settings -*/
-impl Default for Player {
-    fn default() -> Self {
-        Self { suid: String::new(), username: String::new(), displayname: String::new(), statistics: GameStatistics::default() }
-    }
-}
-impl Default for GameStatistics {
-    fn default() -> Self {
-        GameStatistics { games_won: 0, games_played: 0, words_written: 0 }
-    }
-}
-
 /*- PartialEq for checking if player is in room or not -*/
 impl PartialEq for Player {
     fn eq(&self, other: &Self) -> bool {
         self.suid == other.suid
     }
-    fn ne(&self, other: &Self) -> bool {
-        self.suid != other.suid
-    }
 }
 
 
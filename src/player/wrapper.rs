@@ -6,12 +6,12 @@
 /*- Imports -*/
 use serde_derive::{ Serialize, Deserialize };
 use std::{default::Default, net::{SocketAddr, Ipv4Addr}};
-use crate::{ ACCOUNT_MANAGER_URL, Player };
+use crate::{ CONFIG, Player, health::Dependency, metrics };
 
 /*- Player which will be stored in the redis backend, will
     contain the player itself (Player struct) and the
     LocalGameData struct for saving things like snippets -*/
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct PlayerRedisWrapper {
     // Inner values
     pub player: Player,
@@ -22,10 +22,14 @@ pub struct PlayerRedisWrapper {
     // Socket address which is stored in peer map, used to find and remove player
     // from peer map when they disconnect.
     pub socket_addr: String,
+
+    // Server instance holding that socket, see instance.rs
+    #[serde(default)]
+    pub instance: String,
 }
 
 /*- Game data, only lives for the lifetime of a game -*/
-#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
 pub struct LocalGameData {
     // The game start screen will have a whiteboard with all frames
     // containing the player sprites, this coordinate will be where
@@ -40,7 +44,7 @@ impl PlayerRedisWrapper {
     }
 
     /*- Bincode deserialization for transport in websocket tunnels -*/
-    pub fn from_bytes<'lf>(input: &'lf [u8]) -> Result<Self, Box<bincode::ErrorKind>> {
+    pub fn from_bytes(input: &[u8]) -> Result<Self, Box<bincode::ErrorKind>> {
         /*- Deserialize & if fail return -*/
         let player:PlayerRedisWrapper = bincode::deserialize(input)?;
 
@@ -58,17 +62,17 @@ impl PlayerRedisWrapper {
     }
     pub fn to_bytes_unchecked(&self) -> Vec<u8> {
         /*- Serialize & if fail Err(_) -*/
-        bincode::serialize(&self).unwrap_or(Vec::new())
+        bincode::serialize(&self).unwrap_or_default()
     }
 
     /*- Check user auth status -*/
     pub async fn check_auth(jwt:&str) -> u16 {
             /*- GET JWT auth status -*/
-        let url = format!("{}{}", &**ACCOUNT_MANAGER_URL, "profile/verify-token");
-        match reqwest::Client::new()
+        let url = format!("{}{}", CONFIG.account_manager_url, "profile/verify-token");
+        match metrics::timed(Dependency::AccountManager, reqwest::Client::new()
             .get(&url)
             .header("token", jwt)
-            .send().await
+            .send()).await
             { Ok(e) => e, Err(_) => return 402u16 }
             .status()
             .as_u16()
@@ -77,13 +81,15 @@ impl PlayerRedisWrapper {
     /*- Fetch player data by SUID -*/
     pub async fn fetch_player(suid:&str) -> Option<String> {
         /*- Get JSON data -*/
-        let json_fetch:String = reqwest::get(
-            format!(
-                "{}profile/data/by_suid/{}",
-                *ACCOUNT_MANAGER_URL,
-                suid
-            )
-        ).await.ok()?.text().await.ok()?;
+        let json_fetch:String = metrics::timed(Dependency::AccountManager, async {
+            reqwest::get(
+                format!(
+                    "{}profile/data/by_suid/{}",
+                    CONFIG.account_manager_url,
+                    suid
+                )
+            ).await.ok()?.text().await.ok()
+        }).await?;
 
         /*- Return -*/
         Some(json_fetch)
@@ -95,32 +101,11 @@ impl PlayerRedisWrapper {
     }
 }
 
-/*- Default settings -*/
-impl Default for PlayerRedisWrapper {
-    fn default() -> Self {
-        Self {
-            player: Player::default(),
-            local_data: LocalGameData::default(),
-            socket_addr: String::default()
-        }
-    }
-}
-impl Default for LocalGameData {
-    fn default() -> Self {
-        Self {
-            board_position: [0, 0]
-        }
-    }
-}
-
 /*- PartialEq for checking if player is in room or not -*/
 impl PartialEq for PlayerRedisWrapper {
     fn eq(&self, other: &Self) -> bool {
         self.player.suid == other.player.suid
     }
-    fn ne(&self, other: &Self) -> bool {
-        self.player.suid != other.player.suid
-    }
 }
 
 
@@ -0,0 +1,293 @@
+/*- Imports -*/
+use crate::{ handle_req::RequestJsonType, room::Invite, view::{ PublicRoom, Membership }, ws_status, CONFIG };
+use serde_derive::{ Serialize, Deserialize };
+use schemars::JsonSchema;
+use serde_json::{ json, Value };
+use tungstenite::Message;
+use tracing::error;
+
+pub use crate::registry::Encoding;
+
+/*- Outbound frames come in two kinds. Responses answer a request and
+    echo its `request_id` (null if the client didn't send one). Events
+    are pushed by the server unprompted and never carry a request id:
+
+    { "type": "response", "request_id": "42", "status": 200, ... }
+    { "type": "event", "event": "room-closed", ... }
+
+    Clients may speak bincode instead by sending binary frames. Every
+    binary frame, in both directions, is one version byte followed by
+    the bincode encoding of `BinaryRequest` or `Frame`. A connection's
+    encoding is fixed by the first request it sends. -*/
+
+/*- Constants -*/
+pub const TYPE_RESPONSE:&str = "response";
+pub const TYPE_EVENT:&str = "event";
+
+// Bumped whenever a type inside a binary frame changes shape
+pub const BINCODE_VERSION:u8 = 5;
+
+// Protocol versions negotiated by the `hello` request. Clients newer
+// than PROTOCOL_VERSION are answered with it, clients older than
+// MIN_PROTOCOL_VERSION are turned away.
+pub const PROTOCOL_VERSION:u32 = 1;
+pub const MIN_PROTOCOL_VERSION:u32 = 1;
+
+// Optional features a client may ask for in `hello`. Binary frames,
+// request ids & events work for every client, so none are optional yet.
+pub const FEATURES:&[&str] = &[];
+
+/*- Structs, enums & unions -*/
+/*- Binary counterpart of `GeneralRequest` -*/
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BinaryRequest {
+    pub request_id: Option<String>,
+    pub request: RequestJsonType,
+}
+
+/*- Everything the server sends -*/
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Frame {
+    Response {
+        request_id: Option<String>,
+        status: u16,
+
+        // Only set for requests which couldn't be decoded
+        error: Option<String>,
+        body: ResponseBody,
+    },
+    Event(Event),
+}
+
+/*- What a successful request returns -*/
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ResponseBody {
+    Empty,
+    Room(PublicRoom),
+    Hello(HelloResponse),
+    Membership(Membership),
+    Invite(Invite),
+}
+
+/*- Reply to `hello` -*/
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct HelloResponse {
+    pub protocol_version: u32,
+
+    // The subset of the requested features the server agreed to
+    pub features: Vec<String>,
+    pub destinations: Vec<String>,
+    pub limits: Limits,
+}
+
+/*- Limits clients should stay within -*/
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct Limits {
+    pub default_max_players: u8,
+    pub max_players_limit: u8,
+    pub messages_per_second: f64,
+    pub messages_burst: f64,
+    pub room_creations_per_second: f64,
+    pub room_creations_burst: f64,
+    pub heartbeat_interval_ms: u64,
+    pub idle_timeout_ms: u64,
+}
+
+/*- Unsolicited server pushes -*/
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Event {
+    RoomClosed(RoomClosed),
+    ServerShuttingDown(ServerShuttingDown),
+    RoomCredentialRotated(RoomCredentialRotated),
+    RoomUnsubscribed(RoomUnsubscribed),
+}
+
+/*- Event payloads -*/
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct RoomClosed {
+    pub room_id: u32,
+    pub reason: String,
+}
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct ServerShuttingDown {
+    // How long to wait before reconnecting
+    pub reconnect_after_ms: u64,
+}
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct RoomCredentialRotated {
+    pub room_id: u32,
+    pub private_id: String,
+}
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct RoomUnsubscribed {
+    pub room_id: u32,
+    pub reason: String,
+}
+
+/*- Method implementations -*/
+impl Frame {
+    /*- Status of a response, None for events -*/
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Self::Response { status, .. } => Some(*status),
+            Self::Event(_) => None
+        }
+    }
+
+    /*- Encode for a connection speaking `encoding` -*/
+    pub fn encode(&self, encoding:Encoding) -> Message {
+        match encoding {
+            Encoding::Json => Message::Text(self.to_json().to_string()),
+            Encoding::Bincode => {
+                match self.to_bincode() {
+                    Ok(bytes) => Message::Binary(bytes),
+                    Err(e) => {
+                        error!(error = %e, "Could not encode frame");
+
+                        /*- Let the client know its request got a reply it
+                            can't read, or close if even that fails -*/
+                        let request_id = match self {
+                            Self::Response { request_id, .. } => request_id.as_deref(),
+                            Self::Event(_) => None
+                        };
+                        match response(request_id, Err(ws_status::ENCODE_FAILED)).to_bincode() {
+                            Ok(bytes) => Message::Binary(bytes),
+                            Err(_) => Message::Close(None)
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /*- Version byte followed by the bincode payload -*/
+    fn to_bincode(&self) -> bincode::Result<Vec<u8>> {
+        let mut bytes = vec![BINCODE_VERSION];
+        bincode::serialize_into(&mut bytes, self)?;
+
+        Ok(bytes)
+    }
+
+    /*- The JSON protocol flattens everything into one object -*/
+    pub fn to_json(&self) -> Value {
+        match self {
+            Self::Response { request_id, status, error, body } => {
+                let mut envelope = json!({
+                    "type": TYPE_RESPONSE,
+                    "request_id": request_id,
+                    "status": status
+                });
+                if let Some(error) = error {
+                    merge(&mut envelope, json!({ "error": error }));
+                };
+                match body {
+                    ResponseBody::Empty => (),
+                    ResponseBody::Room(room) => merge(&mut envelope, json!({ "room": room.to_string() })),
+                    ResponseBody::Hello(hello) => merge(&mut envelope, serde_json::to_value(hello).unwrap_or_default()),
+                    ResponseBody::Membership(membership) => merge(&mut envelope, json!({
+                        "room": membership.room.to_string(),
+                        "private_id": membership.private_id
+                    })),
+                    ResponseBody::Invite(invite) => merge(&mut envelope, json!({ "invite": invite })),
+                };
+
+                envelope
+            },
+            Self::Event(event) => {
+                let mut envelope = json!({
+                    "type": TYPE_EVENT,
+                    "event": event.name()
+                });
+                merge(&mut envelope, event.to_json());
+
+                envelope
+            }
+        }
+    }
+}
+
+impl Limits {
+    pub fn current() -> Self {
+        Self {
+            default_max_players: CONFIG.default_max_players,
+            max_players_limit: CONFIG.max_players_limit,
+            messages_per_second: CONFIG.messages.per_second,
+            messages_burst: CONFIG.messages.burst,
+            room_creations_per_second: CONFIG.room_creations.per_second,
+            room_creations_burst: CONFIG.room_creations.burst,
+            heartbeat_interval_ms: CONFIG.heartbeat_interval.as_millis() as u64,
+            idle_timeout_ms: CONFIG.idle_timeout.as_millis() as u64,
+        }
+    }
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::RoomClosed(_) => "room-closed",
+            Self::ServerShuttingDown(_) => "server-shutting-down",
+            Self::RoomCredentialRotated(_) => "room-credential-rotated",
+            Self::RoomUnsubscribed(_) => "room-unsubscribed",
+        }
+    }
+    fn to_json(&self) -> Value {
+        match self {
+            Self::RoomClosed(payload) => serde_json::to_value(payload),
+            Self::ServerShuttingDown(payload) => serde_json::to_value(payload),
+            Self::RoomCredentialRotated(payload) => serde_json::to_value(payload),
+            Self::RoomUnsubscribed(payload) => serde_json::to_value(payload),
+        }.unwrap_or_default()
+    }
+}
+
+/*- Functions -*/
+/*- Reply to a request -*/
+pub fn response(request_id:Option<&str>, result:Result<ResponseBody, u16>) -> Frame {
+    let (status, body) = match result {
+        Ok(body) => (200, body),
+        Err(status) => (status, ResponseBody::Empty)
+    };
+
+    Frame::Response { request_id: request_id.map(str::to_string), status, error: None, body }
+}
+
+/*- Reply to a request which couldn't be decoded -*/
+pub fn invalid_request(request_id:Option<&str>, error:String) -> Frame {
+    rejected(request_id, ws_status::INVALID_REQUEST, error)
+}
+
+/*- Reply to a request which was refused before being handled -*/
+pub fn rejected(request_id:Option<&str>, status:u16, error:String) -> Frame {
+    Frame::Response {
+        request_id: request_id.map(str::to_string),
+        status,
+        error: Some(error),
+        body: ResponseBody::Empty
+    }
+}
+
+/*- Unsolicited server push -*/
+pub fn event(event:Event) -> Frame {
+    Frame::Event(event)
+}
+
+/*- Decode a binary frame, Err is the frame to reply with (boxed, frames are large) -*/
+pub fn decode_binary(bytes:&[u8]) -> Result<BinaryRequest, Box<Frame>> {
+    match bytes.split_first() {
+        Some((&BINCODE_VERSION, payload)) => match bincode::deserialize::<BinaryRequest>(payload) {
+            Ok(e) => Ok(e),
+            Err(e) => Err(Box::new(invalid_request(None, e.to_string())))
+        },
+        Some((version, _)) => Err(Box::new(response(None, Err(ws_status::UNSUPPORTED_BINARY_VERSION)))),
+        None => Err(Box::new(invalid_request(None, String::from("empty frame"))))
+    }
+}
+
+/*- Copy every field of `body` into `envelope`, envelope fields win -*/
+fn merge(envelope:&mut Value, body:Value) {
+    if let (Value::Object(envelope), Value::Object(body)) = (envelope, body) {
+        for (key, value) in body {
+            envelope.entry(key).or_insert(value);
+        };
+    };
+}
@@ -0,0 +1,82 @@
+/*- Imports -*/
+use crate::{ config::BucketConfig, session::TokenBucket, ws_status, CONFIG };
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use std::{ hash::Hash, net::IpAddr, time::{ Duration, Instant } };
+
+/*- Limits shared by every connection from the same IP or player, so
+    opening more sockets doesn't buy a client more requests. Limits
+    per connection live in the session. -*/
+
+/*- Constants -*/
+// How often buckets which have refilled & expired blocks are dropped
+const PRUNE_INTERVAL:Duration = Duration::from_secs(30);
+
+/*- Statics -*/
+lazy_static! {
+    static ref CONNECTIONS_BY_IP: DashMap<IpAddr, TokenBucket> = DashMap::new();
+    static ref MESSAGES_BY_IP: DashMap<IpAddr, TokenBucket> = DashMap::new();
+    static ref MESSAGES_BY_SUID: DashMap<String, TokenBucket> = DashMap::new();
+    static ref ROOM_CREATIONS_BY_SUID: DashMap<String, TokenBucket> = DashMap::new();
+
+    // IPs which were closed for abuse, and until when
+    static ref BLOCKED_IPS: DashMap<IpAddr, Instant> = DashMap::new();
+}
+
+/*- Functions -*/
+/*- Checked for every accepted socket, before the handshake -*/
+pub fn allow_connection(ip:&IpAddr) -> bool {
+    if let Some(until) = BLOCKED_IPS.get(ip).map(|until| *until) {
+        if until > Instant::now() { return false };
+        BLOCKED_IPS.remove(ip);
+    };
+
+    take(&CONNECTIONS_BY_IP, *ip, CONFIG.connections_per_ip)
+}
+
+/*- Checked for every request, on top of the connection's own bucket -*/
+pub fn take_message(ip:&IpAddr, suid:Option<&str>) -> Result<(), u16> {
+    if !take(&MESSAGES_BY_IP, *ip, CONFIG.ip_messages) { return Err(ws_status::RATE_LIMITED) };
+    if let Some(suid) = suid {
+        if !take(&MESSAGES_BY_SUID, suid.to_string(), CONFIG.suid_messages) { return Err(ws_status::RATE_LIMITED) };
+    };
+
+    Ok(())
+}
+
+pub fn take_room_creation(suid:&str) -> Result<(), u16> {
+    match take(&ROOM_CREATIONS_BY_SUID, suid.to_string(), CONFIG.room_creations) {
+        true => Ok(()),
+        false => Err(ws_status::RATE_LIMITED)
+    }
+}
+
+/*- Refuse new connections from an IP for RATE_LIMIT_BLOCK_SECONDS -*/
+pub fn block(ip:&IpAddr) {
+    BLOCKED_IPS.insert(*ip, Instant::now() + CONFIG.rate_limit_block);
+}
+
+/*- Take a token from `key`'s bucket, creating it if needed -*/
+pub fn take<K: Eq + Hash>(buckets:&DashMap<K, TokenBucket>, key:K, config:BucketConfig) -> bool {
+    buckets
+        .entry(key)
+        .or_insert_with(|| TokenBucket::new(config.burst, config.per_second))
+        .try_take()
+}
+
+/*- Forget every PRUNE_INTERVAL what no longer limits anyone. A full
+    bucket is the same as no bucket, it's recreated on the next request -*/
+pub async fn run() -> () {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        CONNECTIONS_BY_IP.retain(|_, bucket| !bucket.is_full());
+        MESSAGES_BY_IP.retain(|_, bucket| !bucket.is_full());
+        MESSAGES_BY_SUID.retain(|_, bucket| !bucket.is_full());
+        ROOM_CREATIONS_BY_SUID.retain(|_, bucket| !bucket.is_full());
+
+        let now = Instant::now();
+        BLOCKED_IPS.retain(|_, until| *until > now);
+    };
+}
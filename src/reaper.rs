@@ -0,0 +1,128 @@
+/*- Imports -*/
+use crate::{ room::Room, room_store, instance, metrics, health::Dependency, protocol::{ self, Event, RoomClosed }, ws_status, PeerMap, CONFIG };
+use mongodb::Database;
+use tracing::{ error, info };
+use tungstenite::Message;
+use std::{
+    collections::HashMap,
+    time::Instant,
+};
+
+/*- Why a room was reaped, sent to any players still connected -*/
+#[derive(Clone, Copy, Debug)]
+enum ReapReason {
+    Abandoned,
+    Idle,
+}
+impl ReapReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Abandoned => "abandoned",
+            Self::Idle => "idle",
+        }
+    }
+}
+
+/*- Functions -*/
+/*- Rooms only live as long as the servers holding their players' sockets,
+    so rooms of instances which stopped heartbeating, without players on a
+    live one, are from a crash unless a graceful shutdown suspended them.
+    Suspended rooms are resumed and will be reaped like any other if nobody
+    rejoins them. Needs a heartbeat first, to know which instances are alive. -*/
+pub async fn clear_leftover_rooms(mongodb_connection:&Database) -> () {
+    match room_store::delete_orphaned_rooms(mongodb_connection, &instance::live_instances()).await {
+        Ok(0) => (),
+        Ok(amount) => info!(amount, "Removed leftover rooms"),
+        Err(status) => error!(status, "Could not remove leftover rooms")
+    };
+    match room_store::resume_orphaned_rooms(mongodb_connection, &instance::live_instances(), instance::id()).await {
+        Ok(0) => (),
+        Ok(amount) => info!(amount, "Resumed suspended rooms"),
+        Err(status) => error!(status, "Could not resume suspended rooms")
+    };
+}
+
+/*- Background task sweeping abandoned & idle rooms forever -*/
+pub async fn run(mongodb_connection:Database, peer_map:PeerMap) -> () {
+    // When each room was first seen without any connected players
+    let mut abandoned_since:HashMap<u32, Instant> = HashMap::new();
+    let mut interval = tokio::time::interval(CONFIG.reap_interval);
+
+    loop {
+        interval.tick().await;
+        if !Dependency::Mongodb.is_up() || !instance::is_current() { continue };
+        reap(&mongodb_connection, &peer_map, &mut abandoned_since).await;
+    }
+}
+
+/*- One sweep over our own rooms, and those of instances which stopped -*/
+async fn reap(mongodb_connection:&Database, peer_map:&PeerMap, abandoned_since:&mut HashMap<u32, Instant>) -> () {
+    let mut others:Vec<String> = instance::live_instances();
+    others.retain(|live| live != instance::id());
+    let rooms = match room_store::rooms_not_owned_by(mongodb_connection, &others).await {
+        Ok(e) => e,
+        Err(_) => return
+    };
+    metrics::record_rooms(&rooms);
+
+    /*- Forget rooms which no longer exist -*/
+    abandoned_since.retain(|public_id, _| rooms.iter().any(|room| &room.public_id == public_id));
+
+    let now = Room::unix_now();
+    for room in rooms {
+        let reason = if !has_connected_players(&room, peer_map) {
+            let since = abandoned_since.entry(room.public_id).or_insert_with(Instant::now);
+            if since.elapsed() < CONFIG.abandoned_room_timeout { continue };
+            ReapReason::Abandoned
+        }else {
+            abandoned_since.remove(&room.public_id);
+
+            /*- Players are still around, take over rooms whose owner stopped -*/
+            if !room.is_owned_here() { adopt(mongodb_connection, room.public_id).await; continue };
+            if !room.started || now.saturating_sub(room.last_activity) < CONFIG.idle_started_room_timeout.as_secs() { continue };
+            ReapReason::Idle
+        };
+
+        /*- If the room was modified since we read it, it's not abandoned -*/
+        if let Ok(true) = room_store::delete_room(mongodb_connection, &room).await {
+            info!(room = room.public_id, reason = reason.as_str(), "Reaped room");
+            abandoned_since.remove(&room.public_id);
+            notify_room_closed(&room, peer_map, reason);
+        };
+    };
+}
+
+/*- Check if any of the room's players still have an open websocket. Sockets
+    on other instances are assumed open for as long as that instance is alive,
+    it frees their seats when they disconnect -*/
+fn has_connected_players(room:&Room, peer_map:&PeerMap) -> bool {
+    room.players.iter().any(|player| match player.instance == instance::id() {
+        true => player.socket_addr.parse().is_ok_and(|addr| peer_map.is_connected(&addr)),
+        false => instance::is_alive(&player.instance)
+    })
+}
+
+/*- Become the owner of a room, if nobody changed it meanwhile -*/
+async fn adopt(mongodb_connection:&Database, public_id:u32) -> () {
+    let adopted = room_store::update_room(mongodb_connection, public_id, |room| {
+        if instance::is_alive(&room.owner) { return Err(ws_status::ROOM_UPDATE_CONFLICT) };
+        room.owner = instance::id().to_string();
+        room.suspended = false;
+        Ok(())
+    }).await;
+    if adopted.is_ok() { info!(room = public_id, "Adopted room of a stopped instance") };
+}
+
+/*- Tell stragglers connected here their room is gone -*/
+fn notify_room_closed(room:&Room, peer_map:&PeerMap, reason:ReapReason) {
+    let frame = protocol::event(Event::RoomClosed(RoomClosed {
+        room_id: room.public_id,
+        reason: reason.as_str().to_string()
+    }));
+
+    let local = room.players.iter().filter(|player| player.instance == instance::id());
+    for addr in local.filter_map(|player| player.socket_addr.parse().ok()) {
+        peer_map.send(&addr, frame.encode(peer_map.encoding(&addr)));
+    };
+    peer_map.close_room(room.public_id);
+}
@@ -0,0 +1,218 @@
+/*- Imports -*/
+use dashmap::DashMap;
+use futures_channel::mpsc::UnboundedSender;
+use tungstenite::Message;
+use std::{
+    collections::HashSet,
+    net::{ IpAddr, SocketAddr },
+    sync::atomic::{ AtomicU64, Ordering },
+};
+
+/*- Types -*/
+type Tx = UnboundedSender<Message>;
+
+/*- Structs, enums & unions -*/
+/*- Every open websocket connection, indexed by address, SUID & room.
+    Each map is sharded internally, so lookups from different
+    connections don't contend on one lock. No guard is ever handed
+    out, which means nothing can be held across an await. -*/
+#[derive(Default)]
+pub struct ConnectionRegistry {
+    connections: DashMap<SocketAddr, Connection>,
+
+    // SUID -> every socket that player has open
+    by_suid: DashMap<String, HashSet<SocketAddr>>,
+
+    // Public room id -> sockets in that room
+    rooms: DashMap<u32, HashSet<SocketAddr>>,
+
+    // Text & binary messages sent through the registry, for metrics
+    pushed: AtomicU64,
+}
+
+/*- State kept for one connection -*/
+struct Connection {
+    sender: Tx,
+
+    // How the connection wants its frames encoded
+    encoding: Encoding,
+
+    // Set once the connection has authenticated
+    suid: Option<String>,
+
+    // Public ids of the rooms this connection is in
+    rooms: HashSet<u32>,
+}
+
+/*- Wire format a connection speaks, JSON until it negotiates otherwise -*/
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum Encoding {
+    #[default]
+    Json,
+    Bincode,
+}
+
+/*- Method implementations -*/
+impl ConnectionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /*- Register a newly accepted connection -*/
+    pub fn insert(&self, addr:SocketAddr, sender:Tx) {
+        self.connections.insert(addr, Connection { sender, encoding: Encoding::default(), suid: None, rooms: HashSet::new() });
+    }
+
+    /*- Remove a connection and every index entry pointing at it -*/
+    pub fn remove(&self, addr:&SocketAddr) {
+        let (_, connection) = match self.connections.remove(addr) {
+            Some(e) => e,
+            None => return
+        };
+
+        if let Some(suid) = connection.suid {
+            self.by_suid.remove_if_mut(&suid, |_, addrs| { addrs.remove(addr); addrs.is_empty() });
+        };
+        for public_id in connection.rooms {
+            self.rooms.remove_if_mut(&public_id, |_, addrs| { addrs.remove(addr); addrs.is_empty() });
+        };
+    }
+
+    /*- Remember which player a connection belongs to -*/
+    pub fn set_suid(&self, addr:&SocketAddr, suid:&str) {
+        let previous = match self.connections.get_mut(addr) {
+            Some(mut connection) => connection.suid.replace(suid.to_string()),
+            None => return
+        };
+
+        if let Some(previous) = previous.filter(|previous| previous != suid) {
+            self.by_suid.remove_if_mut(&previous, |_, addrs| { addrs.remove(addr); addrs.is_empty() });
+        };
+        self.by_suid.entry(suid.to_string()).or_default().insert(*addr);
+    }
+
+    /*- Remember which encoding a connection negotiated -*/
+    pub fn set_encoding(&self, addr:&SocketAddr, encoding:Encoding) {
+        if let Some(mut connection) = self.connections.get_mut(addr) {
+            connection.encoding = encoding;
+        };
+    }
+
+    /*- Add / remove a connection to / from a room's index -*/
+    pub fn join_room(&self, addr:&SocketAddr, public_id:u32) {
+        match self.connections.get_mut(addr) {
+            Some(mut connection) => connection.rooms.insert(public_id),
+            None => return
+        };
+        self.rooms.entry(public_id).or_default().insert(*addr);
+    }
+    pub fn leave_room(&self, addr:&SocketAddr, public_id:u32) {
+        if let Some(mut connection) = self.connections.get_mut(addr) {
+            connection.rooms.remove(&public_id);
+        };
+        self.rooms.remove_if_mut(&public_id, |_, addrs| { addrs.remove(addr); addrs.is_empty() });
+    }
+
+    /*- Drop a room from the index, e.g. when it's deleted -*/
+    pub fn close_room(&self, public_id:u32) {
+        let addrs = match self.rooms.remove(&public_id) {
+            Some((_, addrs)) => addrs,
+            None => return
+        };
+
+        for addr in addrs {
+            if let Some(mut connection) = self.connections.get_mut(&addr) {
+                connection.rooms.remove(&public_id);
+            };
+        };
+    }
+
+    /*- Lookups -*/
+    pub fn sender(&self, addr:&SocketAddr) -> Option<Tx> {
+        self.connections.get(addr).map(|connection| connection.sender.clone())
+    }
+    pub fn is_connected(&self, addr:&SocketAddr) -> bool {
+        self.connections.contains_key(addr)
+    }
+    pub fn encoding(&self, addr:&SocketAddr) -> Encoding {
+        self.connections.get(addr).map(|connection| connection.encoding).unwrap_or_default()
+    }
+    pub fn suid(&self, addr:&SocketAddr) -> Option<String> {
+        self.connections.get(addr).and_then(|connection| connection.suid.clone())
+    }
+    pub fn addrs_of_suid(&self, suid:&str) -> Vec<SocketAddr> {
+        match self.by_suid.get(suid) {
+            Some(addrs) => addrs.iter().copied().collect(),
+            None => Vec::new()
+        }
+    }
+    pub fn addrs_with_ip(&self, ip:&IpAddr) -> Vec<SocketAddr> {
+        self.connections
+            .iter()
+            .map(|connection| *connection.key())
+            .filter(|addr| &addr.ip() == ip)
+            .collect()
+    }
+    pub fn room_members(&self, public_id:u32) -> Vec<SocketAddr> {
+        match self.rooms.get(&public_id) {
+            Some(addrs) => addrs.iter().copied().collect(),
+            None => Vec::new()
+        }
+    }
+    pub fn rooms_of(&self, addr:&SocketAddr) -> Vec<u32> {
+        match self.connections.get(addr) {
+            Some(connection) => connection.rooms.iter().copied().collect(),
+            None => Vec::new()
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+    pub fn messages_pushed(&self) -> u64 {
+        self.pushed.load(Ordering::Relaxed)
+    }
+
+    /*- Sending, returns false if the connection is gone -*/
+    pub fn send(&self, addr:&SocketAddr, message:Message) -> bool {
+        match self.connections.get(addr) {
+            Some(connection) => self.push(&connection.sender, message),
+            None => false
+        }
+    }
+    pub fn send_to_room(&self, public_id:u32, message:&Message) {
+        for addr in self.room_members(public_id) {
+            self.send(&addr, message.clone());
+        };
+    }
+    pub fn send_to_suid(&self, suid:&str, message:&Message) {
+        for addr in self.addrs_of_suid(suid) {
+            self.send(&addr, message.clone());
+        };
+    }
+    pub fn broadcast(&self, message:&Message) {
+        for connection in self.connections.iter() {
+            self.push(&connection.sender, message.clone());
+        };
+    }
+
+    /*- Like `broadcast`, but encodes the message once per encoding in use -*/
+    pub fn broadcast_encoded(&self, encode:impl Fn(Encoding) -> Message) {
+        let (mut json, mut bincode) = (None, None);
+        for connection in self.connections.iter() {
+            let message = match connection.encoding {
+                Encoding::Json => json.get_or_insert_with(|| encode(Encoding::Json)),
+                Encoding::Bincode => bincode.get_or_insert_with(|| encode(Encoding::Bincode)),
+            };
+            self.push(&connection.sender, message.clone());
+        };
+    }
+
+    /*- Pings & close frames aren't counted -*/
+    fn push(&self, sender:&Tx, message:Message) -> bool {
+        let counted = message.is_text() || message.is_binary();
+        let sent = sender.unbounded_send(message).is_ok();
+        if sent && counted { self.pushed.fetch_add(1, Ordering::Relaxed); };
+
+        sent
+    }
+}
@@ -1,9 +1,10 @@
 /*- Global allowings -*/
 
 /*- Imports -*/
-use crate::{ player::Player as PlayerInner, wrapper::PlayerRedisWrapper as PlayerWrpd, ws_status, ACCOUNT_MANAGER_URL };
+use crate::{ player::Player as PlayerInner, wrapper::PlayerRedisWrapper as PlayerWrpd, health::Dependency, instance, metrics, ws_status, CONFIG };
 use serde_derive::{ Serialize, Deserialize };
-use reqwest;
+use tracing::error;
+use std::net::SocketAddr;
 
 
 /*- Struct for retrieving suid data from token auth response -*/
@@ -13,13 +14,13 @@ struct SuidResponse {
 }
 
 /*- Functions -*/
-pub async fn authorize_player<'a>(jwt:&'a str) -> Result<PlayerWrpd, u16> {
-    let token_check_url = format!("{}profile/verify-token", &*ACCOUNT_MANAGER_URL);
+pub async fn authorize_player(jwt:&str) -> Result<PlayerWrpd, u16> {
+    let token_check_url = format!("{}profile/verify-token", CONFIG.account_manager_url);
 
     /*- Check player auth -*/
-    let suid:String = match reqwest::Client::new()
+    let suid:String = match metrics::timed(Dependency::AccountManager, reqwest::Client::new()
         .get(token_check_url)
-        .header("token", jwt).send().await {
+        .header("token", jwt).send()).await {
 
         /*- If request succeeded -*/
         Ok(response) => {
@@ -38,18 +39,33 @@ pub async fn authorize_player<'a>(jwt:&'a str) -> Result<PlayerWrpd, u16> {
     };
 
     /*- Get player -*/
-    println!("SUID: {suid}");
     let fetched_player = PlayerWrpd::fetch_player(&suid).await;
     match fetched_player {
         Some(string) => {
 
             /*- Deserialize the player data and wrap it in a wrapper -*/
-            println!("TOOO {}", string.as_str());
-            Ok(match serde_json::from_str::<PlayerInner>(string.as_str()) {
-                Ok(e) => PlayerWrpd::from_inner(e),
-                Err(e) => panic!("{e}")
-            })
+            match serde_json::from_str::<PlayerInner>(string.as_str()) {
+                Ok(e) => Ok(PlayerWrpd::from_inner(e)),
+                Err(e) => {
+                    error!(error = %e, "Could not parse player from the account manager");
+                    Err(ws_status::PLAYER_PARSE)
+                }
+            }
+        },
+        None => Err(ws_status::PLAYER_PARSE)
+    }
+}
+
+/*- Check JWT auth status and fetch the player behind it, tagged with their socket address -*/
+pub async fn authenticate(jwt:&str, socket_addr:SocketAddr) -> Result<PlayerWrpd, u16> {
+    match PlayerWrpd::check_auth(jwt).await {
+        200 => {
+            let mut player = authorize_player(jwt).await?;
+            player.socket_addr = socket_addr.to_string();
+            player.instance = instance::id().to_string();
+
+            Ok(player)
         },
-        None => return Err(ws_status::PLAYER_PARSE)
+        _ => Err(ws_status::UNAUTHORIZED)
     }
 }
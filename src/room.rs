@@ -1,10 +1,10 @@
 /*- Imports -*/
-use crate::{ player::Player as PlayerInner, wrapper::PlayerRedisWrapper as PlayerWrpd };
+use crate::{ access, instance, player::Player as PlayerInner, wrapper::PlayerRedisWrapper as PlayerWrpd, CONFIG };
 use rand::Rng;
 use serde_derive::{ Serialize, Deserialize };
+use schemars::JsonSchema;
 use uuid::Uuid;
-use mongodb;
-use std::{ default::Default, collections::{BTreeMap, btree_map::Range}, net::SocketAddr };
+use std::{ default::Default, collections::{BTreeMap, btree_map::Range}, net::SocketAddr, time::{ SystemTime, UNIX_EPOCH } };
 
 /*- Constants -*/
 mod private_room_id_range {
@@ -14,8 +14,11 @@ mod private_room_id_range {
     pub const RANGE:std::ops::Range<u32> = 10_000..100_000;
 }
 
+// Outstanding invite codes kept per room
+const MAX_INVITES:usize = 32;
+
 /*- Structs, enums & unions -*/
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Room {
     // Room-id for sending room specific websocket data. Players
     // will recieve this id upon joining a room and connecting
@@ -52,6 +55,66 @@ pub struct Room {
     // is not found in this vector, the player is not in the room. And no
     // need to iterate over the vector.
     pub player_adresses : Vec<String>,
+
+    // Incremented on every write to mongodb. Mutations compare-and-swap
+    // against it so that two players modifying the same room at once
+    // can't overwrite each other's changes.
+    #[serde(default)]
+    pub version         : u64,
+
+    // Unix timestamp (seconds) of the last write to this room. Used
+    // by the reaper to find started rooms which have gone idle.
+    #[serde(default)]
+    pub last_activity   : u64,
+
+    // Set when the server shuts down gracefully with this room still
+    // alive, so that the next start keeps it for players to rejoin
+    // instead of treating it as a leftover from a crash.
+    #[serde(default)]
+    pub suspended       : bool,
+
+    // Argon2 hash of the room password, players need either
+    // the password or an invite code to join if it's set
+    #[serde(default)]
+    pub password_hash   : Option<String>,
+
+    // Only players with an invite code may join
+    #[serde(default)]
+    pub invite_only     : bool,
+
+    // Invite codes handed out by the leader
+    #[serde(default)]
+    pub invites         : Vec<Invite>,
+
+    // Players the leader kicked & banned
+    #[serde(default)]
+    pub bans            : Vec<RoomBan>,
+
+    // Server instance which created the room, or adopted it after its
+    // creator stopped. Only the owner suspends, resumes & reaps it.
+    #[serde(default)]
+    pub owner           : String,
+}
+
+/*- Player banned from a room -*/
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomBan {
+    pub suid        : String,
+
+    // Also keeps out anyone else connecting from here
+    pub ip          : Option<String>,
+}
+
+/*- Invite code issued by the room's leader -*/
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Invite {
+    pub code        : String,
+
+    // Removed the first time it's used
+    pub single_use  : bool,
+
+    // Unix timestamp (seconds) after which the code stops working
+    pub expires_at  : Option<u64>,
 }
 
 /*- Method implementations -*/
@@ -66,44 +129,48 @@ impl Room {
             public_id,
             players: vec![leader.clone()],
             _players: Vec::new(),
-            max_players: 5,
+            max_players: CONFIG.default_max_players,
             leader: leader.clone(),
             started: false,
             private: false,
             player_adresses: vec![leader.socket_addr],
+            version: 0,
+            last_activity: Self::unix_now(),
+            suspended: false,
+            password_hash: None,
+            invite_only: false,
+            invites: Vec::new(),
+            bans: Vec::new(),
+            owner: instance::id().to_string(),
         }
     }
 
     /*- Remove player from room -*/
     pub fn remove_player(&mut self, player:PlayerWrpd) -> Result<(), ()> {
-        /*- First check if player is leader -*/
-        if player.player.suid == self.leader.player.suid {
-            self.players.remove(0);
-
-            /*- Get next player as leader -*/
-            self.leader = match self.players.iter().next() {
-                Some(player) => player.clone(),
-                None => return Err(self.disbandon())
-            };
+        /*- Find player -*/
+        let index = match self.players.iter().position(|p| p.player.suid == player.player.suid) {
+            Some(index) => index,
+            None => return Err(())
+        };
+        let removed = self.players.remove(index);
+        self.player_adresses.retain(|addr| addr != &removed.socket_addr);
 
-            Ok(())
-        }else {
-            /*- Iterate over players -*/
-            for (index, p) in self.players.iter().enumerate() {
-                if p.player.suid == player.player.suid {
-                    self.players.remove(index);
-                    return Ok(())
-                };
+        /*- If player was leader, pass the role onto the next player who joined -*/
+        if removed.player.suid == self.leader.player.suid {
+            match self.players.first() {
+                Some(player) => self.leader = player.clone(),
+                None => self.disbandon()
             };
+        };
 
-            Err(())
-        }
+        Ok(())
     }
 
     /*- Add player to room -*/
     pub fn add_player(&mut self, player:PlayerWrpd) -> Result<(), ()> {
         /*- If room still fits another player -*/
         if self.players.len() < self.max_players as usize {
+            self.player_adresses.push(player.socket_addr.clone());
             self.players.push(player);
             return Ok(())
         };
@@ -111,10 +178,81 @@ impl Room {
         Err(())
     }
 
+    /*- Check if player is in room -*/
+    pub fn has_player(&self, suid:&str) -> bool {
+        self.players.iter().any(|p| p.player.suid == suid)
+    }
+
+    /*- Point a player who is already in the room to their new socket, e.g. after
+        reconnecting. The socket may be held by another instance than before -*/
+    pub fn update_player_address(&mut self, suid:&str, socket_addr:String, instance:String) {
+        if let Some(player) = self.players.iter_mut().find(|p| p.player.suid == suid) {
+            let old_addr = std::mem::replace(&mut player.socket_addr, socket_addr.clone());
+            player.instance = instance.clone();
+            self.player_adresses.retain(|addr| addr != &old_addr);
+            self.player_adresses.push(socket_addr.clone());
+        };
+        if self.leader.player.suid == suid {
+            self.leader.socket_addr = socket_addr;
+            self.leader.instance = instance;
+        };
+    }
+
+    /*- Whether this instance is responsible for the room -*/
+    pub fn is_owned_here(&self) -> bool {
+        self.owner == instance::id()
+    }
+
+    /*- Remove a player on the leader's behalf. The private id is
+        rotated so the kicked player's copy of it stops working -*/
+    pub fn kick_player(&mut self, suid:&str) -> Result<PlayerWrpd, ()> {
+        let player = match self.players.iter().find(|p| p.player.suid == suid) {
+            Some(player) => player.clone(),
+            None => return Err(())
+        };
+        self.remove_player(player.clone())?;
+        self.private_id = Self::gen_private_id();
+
+        Ok(player)
+    }
+
+    /*- Keep a player (and optionally their IP) from rejoining -*/
+    pub fn ban(&mut self, suid:&str, ip:Option<String>) {
+        self.bans.retain(|ban| ban.suid != suid);
+        self.bans.push(RoomBan { suid: suid.to_string(), ip });
+    }
+    pub fn unban(&mut self, suid:&str) {
+        self.bans.retain(|ban| ban.suid != suid);
+    }
+    pub fn is_banned(&self, suid:&str, ip:&str) -> bool {
+        self.bans.iter().any(|ban| ban.suid == suid || ban.ip.as_deref() == Some(ip))
+    }
+
+    /*- Compare a private id sent by a client, in constant time -*/
+    pub fn check_private_id(&self, private_id:&str) -> bool {
+        access::constant_time_eq(&self.private_id, private_id)
+    }
+
+    /*- Check if player is leader -*/
+    pub fn is_leader(&self, suid:&str) -> bool {
+        self.leader.player.suid == suid
+    }
+
+    /*- Pass the leader role onto another player in the room -*/
+    pub fn transfer_leadership(&mut self, suid:&str) -> Result<(), ()> {
+        match self.players.iter().find(|p| p.player.suid == suid) {
+            Some(player) => {
+                self.leader = player.clone();
+                Ok(())
+            },
+            None => Err(())
+        }
+    }
+
     /*- Change room size -*/
     pub fn change_max_players(&mut self, max_players:u8) -> Result<(), ()> {
-        /*- If the change of max players won't fit the current amount of players in the room -*/
-        if self.players.len() > max_players as usize {
+        /*- If the change of max players won't fit the current amount of players in the room, or exceeds the server limit -*/
+        if self.players.len() > max_players as usize || max_players > CONFIG.max_players_limit {
             Err(())
         }else {
             self.max_players = max_players;
@@ -122,8 +260,39 @@ impl Room {
         }
     }
 
+    /*- Store a new invite, dropping expired ones and, if there
+        are still too many, the oldest -*/
+    pub fn add_invite(&mut self, invite:Invite) {
+        let now = Self::unix_now();
+        self.invites.retain(|invite| invite.expires_at.is_none_or(|expires_at| expires_at > now));
+        if self.invites.len() >= MAX_INVITES {
+            self.invites.remove(0);
+        };
+        self.invites.push(invite);
+    }
+
+    /*- Check an invite code, using it up if it's single use -*/
+    pub fn redeem_invite(&mut self, code:&str) -> Result<(), ()> {
+        let now = Self::unix_now();
+        let index = match self.invites.iter().position(|invite| invite.code == code) {
+            Some(index) => index,
+            None => return Err(())
+        };
+
+        let invite = &self.invites[index];
+        if invite.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            self.invites.remove(index);
+            return Err(())
+        };
+        if invite.single_use {
+            self.invites.remove(index);
+        };
+
+        Ok(())
+    }
+
     /*- Change room visibility -*/
-    pub fn change_room_visibility(&mut self, private:bool) -> () {
+    pub fn change_room_visibility(&mut self, private:bool) {
         self.private = private;
     }
 
@@ -186,12 +355,9 @@ impl Room {
     // }
 
     /*- Serialize players -*/
-    pub fn serialize_players_unchecked(&mut self) -> () {
+    pub fn serialize_players_unchecked(&mut self) {
         /*- Serialize & if fail Err(_) -*/
-        self._players = match bincode::serialize(&self.players) {
-            Ok(e) => e,
-            Err(_) => Vec::new()
-        };
+        self._players = bincode::serialize(&self.players).unwrap_or_default();
     }
 
     /*- Create ID for room -*/
@@ -202,19 +368,22 @@ impl Room {
         rand::thread_rng().gen_range(private_room_id_range::RANGE)
     }
 
-    /*- Debugging -*/
-    pub fn quick_display(&self, title:&str) -> () {
-        let s:usize = 28 + self.leader.player.username.len() + self.public_id.to_string().len() + self.players.len().to_string().len();
-        println!("┌{}┐", "─".repeat(s));
-        println!("│{:^width$}│", title, width = s);
-        println!("├{}┤", "─".repeat(s));
-        println!("│ {}'s room | room:{} | {} players │", self.leader.player.username, self.public_id, self.players.len());
-        println!("└{}┘", "─".repeat(s));
+    /*- Mark room as active -*/
+    pub fn touch(&mut self) {
+        self.last_activity = Self::unix_now();
+    }
+    pub fn unix_now() -> u64 {
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(e) => e.as_secs(),
+            Err(_) => 0
+        }
     }
 
-    /*- Disbandon room -*/
-    pub fn disbandon(&mut self) -> () {
-        todo!()
+    /*- Disbandon room. Rooms without players are deleted from mongodb on the next write -*/
+    pub fn disbandon(&mut self) {
+        self.players.clear();
+        self.player_adresses.clear();
+        self.leader = PlayerWrpd::default();
     }
 }
 impl Default for Room {
@@ -228,12 +397,103 @@ impl Default for Room {
             leader: PlayerWrpd::default(), 
             started: false, 
             private: false,
-            player_adresses: Vec::new()
+            player_adresses: Vec::new(),
+            version: 0,
+            last_activity: 0,
+            suspended: false,
+            password_hash: None,
+            invite_only: false,
+            invites: Vec::new(),
+            bans: Vec::new(),
+            owner: String::new(),
         }
     }
 }
-impl ToString for Room {
-    fn to_string(&self) -> String {
-        serde_json::to_string(self).unwrap_or(String::new())
+
+/*- Tests -*/
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn player(suid:&str) -> PlayerWrpd {
+        let mut player = PlayerWrpd::default();
+        player.player.suid = suid.to_string();
+        player.socket_addr = format!("{suid}-addr");
+        player
+    }
+
+    /*- A room led by the first of `suids`, everyone already joined -*/
+    fn room(suids:&[&str]) -> Room {
+        let mut room = Room { leader: player(suids[0]), ..Room::default() };
+        for suid in suids {
+            room.add_player(player(suid)).unwrap();
+        };
+        room
+    }
+
+    #[test]
+    fn removing_a_player_drops_their_address() {
+        let mut room = room(&["a", "b", "c"]);
+        room.remove_player(player("b")).unwrap();
+
+        assert!(!room.has_player("b"));
+        assert_eq!(room.player_adresses, vec!["a-addr", "c-addr"]);
+        assert!(room.is_leader("a"));
+    }
+
+    #[test]
+    fn removing_an_unknown_player_fails() {
+        let mut room = room(&["a", "b"]);
+        assert!(room.remove_player(player("x")).is_err());
+        assert_eq!(room.players.len(), 2);
+    }
+
+    #[test]
+    fn leader_role_passes_to_the_next_player_who_joined() {
+        let mut room = room(&["a", "b", "c"]);
+        room.remove_player(player("a")).unwrap();
+
+        assert!(room.is_leader("b"));
+        assert_eq!(room.players.len(), 2);
+    }
+
+    #[test]
+    fn last_player_leaving_disbands_the_room() {
+        let mut room = room(&["a"]);
+        room.remove_player(player("a")).unwrap();
+
+        assert!(room.players.is_empty());
+        assert!(room.player_adresses.is_empty());
+        assert!(room.leader.player.suid.is_empty());
+    }
+
+    #[test]
+    fn kicking_a_player_rotates_the_private_id() {
+        let mut room = room(&["a", "b"]);
+        room.private_id = "old".to_string();
+        let kicked = room.kick_player("b").unwrap();
+
+        assert_eq!(kicked.player.suid, "b");
+        assert!(!room.has_player("b"));
+        assert!(!room.check_private_id("old"));
+    }
+
+    #[test]
+    fn kicking_an_unknown_player_keeps_the_private_id() {
+        let mut room = room(&["a", "b"]);
+        room.private_id = "old".to_string();
+
+        assert!(room.kick_player("x").is_err());
+        assert!(room.check_private_id("old"));
+    }
+
+    #[test]
+    fn leadership_only_transfers_to_players_in_the_room() {
+        let mut room = room(&["a", "b"]);
+
+        assert!(room.transfer_leadership("x").is_err());
+        assert!(room.is_leader("a"));
+        room.transfer_leadership("b").unwrap();
+        assert!(room.is_leader("b"));
     }
 }
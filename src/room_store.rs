@@ -0,0 +1,198 @@
+/*- Imports -*/
+use crate::{ room::Room, ws_status };
+use mongodb::{
+    bson::doc,
+    error::{ Error, ErrorKind, WriteFailure },
+    options::IndexOptions,
+    Collection, Database, IndexModel,
+};
+use futures_util::TryStreamExt;
+use tracing::error;
+
+/*- Constants -*/
+const ROOMS_COLLECTION:&str = "rooms";
+
+// How many times a room mutation is re-applied when another request
+// modified the same room between our read and our write.
+const MAX_UPDATE_ATTEMPTS:usize = 16;
+
+// Mongodb's code for a write violating a unique index
+const DUPLICATE_KEY:i32 = 11000;
+
+/*- Functions -*/
+pub fn rooms(mongodb_connection:&Database) -> Collection<Room> {
+    mongodb_connection.collection::<Room>(ROOMS_COLLECTION)
+}
+
+/*- Make room lookups fast & room ids unique. Creating an index which already exists is a no-op -*/
+pub async fn create_indexes(mongodb_connection:&Database) -> () {
+    let unique = || IndexOptions::builder().unique(true).build();
+    let indexes = vec![
+        IndexModel::builder().keys(doc! { "public_id": 1 }).options(unique()).build(),
+        IndexModel::builder().keys(doc! { "private_id": 1 }).options(unique()).build(),
+        IndexModel::builder().keys(doc! { "players.player.suid": 1 }).build(),
+    ];
+
+    if let Err(e) = rooms(mongodb_connection).create_indexes(indexes, None).await {
+        error!(error = %e, "Could not create room indexes");
+    };
+}
+
+/*- Insert a newly created room. Another room may have taken its ids
+    between picking and inserting them, then new ones are picked -*/
+pub async fn insert_room(mongodb_connection:&Database, room:&mut Room) -> Result<(), u16> {
+    for _ in 0..MAX_UPDATE_ATTEMPTS {
+        match rooms(mongodb_connection).insert_one(&*room, None).await {
+            Ok(_) => return Ok(()),
+            Err(e) if is_duplicate_key(&e) => {
+                room.public_id = gen_free_public_id(mongodb_connection).await?;
+                room.private_id = Room::gen_private_id();
+            },
+            Err(_) => return Err(ws_status::DATABASE_ERROR)
+        };
+    };
+
+    Err(ws_status::NO_FREE_ROOM_ID)
+}
+
+fn is_duplicate_key(error:&Error) -> bool {
+    match &*error.kind {
+        ErrorKind::Write(WriteFailure::WriteError(e)) => e.code == DUPLICATE_KEY,
+        _ => false
+    }
+}
+
+/*- Generate a public id which no other room currently holds -*/
+pub async fn gen_free_public_id(mongodb_connection:&Database) -> Result<u32, u16> {
+    for _ in 0..MAX_UPDATE_ATTEMPTS {
+        let public_id:u32 = Room::gen_public_id();
+        match rooms(mongodb_connection).count_documents(doc! { "public_id": public_id }, None).await {
+            Ok(0) => return Ok(public_id),
+            Ok(_) => continue,
+            Err(_) => return Err(ws_status::DATABASE_ERROR)
+        };
+    };
+
+    Err(ws_status::NO_FREE_ROOM_ID)
+}
+
+/*- Delete a room, but only if nobody modified it since it was read -*/
+pub async fn delete_room(mongodb_connection:&Database, room:&Room) -> Result<bool, u16> {
+    let filter = doc! { "public_id": room.public_id, "version": room.version as i64 };
+    match rooms(mongodb_connection).delete_one(filter, None).await {
+        Ok(result) => Ok(result.deleted_count == 1),
+        Err(_) => Err(ws_status::DATABASE_ERROR)
+    }
+}
+
+/*- Every room except those owned by `instances` -*/
+pub async fn rooms_not_owned_by(mongodb_connection:&Database, instances:&[String]) -> Result<Vec<Room>, u16> {
+    let cursor = match rooms(mongodb_connection).find(doc! { "owner": { "$nin": instances } }, None).await {
+        Ok(e) => e,
+        Err(_) => return Err(ws_status::DATABASE_ERROR)
+    };
+
+    match cursor.try_collect::<Vec<Room>>().await {
+        Ok(e) => Ok(e),
+        Err(_) => Err(ws_status::CORRUPTED_ROOM)
+    }
+}
+
+/*- Delete rooms whose owner & players are all on instances which aren't in `live`,
+    unless they were suspended by a graceful shutdown. Returns how many were removed -*/
+pub async fn delete_orphaned_rooms(mongodb_connection:&Database, live:&[String]) -> Result<u64, u16> {
+    let filter = doc! {
+        "owner": { "$nin": live },
+        "suspended": { "$ne": true },
+        "players": { "$not": { "$elemMatch": { "instance": { "$in": live } } } },
+    };
+    match rooms(mongodb_connection).delete_many(filter, None).await {
+        Ok(result) => Ok(result.deleted_count),
+        Err(_) => Err(ws_status::DATABASE_ERROR)
+    }
+}
+
+/*- Mark every room `owner` owns as suspended, returns how many were changed -*/
+pub async fn suspend_rooms_of(mongodb_connection:&Database, owner:&str) -> Result<u64, u16> {
+    let update = doc! { "$set": { "suspended": true }, "$inc": { "version": 1_i64 } };
+    match rooms(mongodb_connection).update_many(doc! { "owner": owner, "suspended": { "$ne": true } }, update, None).await {
+        Ok(result) => Ok(result.modified_count),
+        Err(_) => Err(ws_status::DATABASE_ERROR)
+    }
+}
+
+/*- Resume the suspended rooms of instances which aren't in `live`, and
+    make `owner` their new owner. Returns how many were changed -*/
+pub async fn resume_orphaned_rooms(mongodb_connection:&Database, live:&[String], owner:&str) -> Result<u64, u16> {
+    let filter = doc! { "owner": { "$nin": live }, "suspended": true };
+    let update = doc! { "$set": { "suspended": false, "owner": owner }, "$inc": { "version": 1_i64 } };
+    match rooms(mongodb_connection).update_many(filter, update, None).await {
+        Ok(result) => Ok(result.modified_count),
+        Err(_) => Err(ws_status::DATABASE_ERROR)
+    }
+}
+
+/*- Get room by public id -*/
+pub async fn get_room(mongodb_connection:&Database, public_id:u32) -> Result<Room, u16> {
+    match rooms(mongodb_connection).find_one(doc! { "public_id": public_id }, None).await {
+        Ok(Some(room)) => Ok(room),
+        Ok(None) => Err(ws_status::ROOM_NOT_FOUND),
+        Err(_) => Err(ws_status::CORRUPTED_ROOM)
+    }
+}
+
+/*- Every room a player is in, across all server instances -*/
+pub async fn rooms_of_player(mongodb_connection:&Database, suid:&str) -> Result<Vec<Room>, u16> {
+    match rooms(mongodb_connection).find(doc! { "players.player.suid": suid }, None).await {
+        Ok(cursor) => match cursor.try_collect().await {
+            Ok(e) => Ok(e),
+            Err(_) => Err(ws_status::CORRUPTED_ROOM)
+        },
+        Err(_) => Err(ws_status::DATABASE_ERROR)
+    }
+}
+pub async fn count_rooms_of_player(mongodb_connection:&Database, suid:&str) -> Result<u64, u16> {
+    match rooms(mongodb_connection).count_documents(doc! { "players.player.suid": suid }, None).await {
+        Ok(e) => Ok(e),
+        Err(_) => Err(ws_status::DATABASE_ERROR)
+    }
+}
+
+/*- Read-modify-write a room. The write only succeeds if the room's
+    version is still the one we read, otherwise someone else changed
+    the room in between and the mutation is re-applied on the fresh
+    document. Rooms left without players are deleted instead. -*/
+pub async fn update_room<F>(mongodb_connection:&Database, public_id:u32, mut mutation:F) -> Result<Room, u16>
+    where F: FnMut(&mut Room) -> Result<(), u16>
+{
+    for _ in 0..MAX_UPDATE_ATTEMPTS {
+        let mut room = get_room(mongodb_connection, public_id).await?;
+        let read_version = room.version;
+
+        /*- Apply mutation, errors here are final because they were made on up-to-date data -*/
+        mutation(&mut room)?;
+        room.version = read_version + 1;
+        room.touch();
+
+        /*- Compare & swap -*/
+        let filter = doc! { "public_id": public_id, "version": read_version as i64 };
+        let swapped:bool = if room.players.is_empty() {
+            match rooms(mongodb_connection).delete_one(filter, None).await {
+                Ok(result) => result.deleted_count == 1,
+                Err(_) => return Err(ws_status::DATABASE_ERROR)
+            }
+        }else {
+            match rooms(mongodb_connection).replace_one(filter, &room, None).await {
+                Ok(result) => result.matched_count == 1,
+                Err(_) => return Err(ws_status::DATABASE_ERROR)
+            }
+        };
+
+        if swapped { return Ok(room) };
+
+        /*- Let the competing operation finish before retrying -*/
+        tokio::task::yield_now().await;
+    };
+
+    Err(ws_status::ROOM_UPDATE_CONFLICT)
+}
@@ -0,0 +1,182 @@
+/*- Imports -*/
+use crate::{
+    handle_req::{
+        GeneralRequest,
+        RequestJsonType,
+        CreateRoomRequestData,
+        JoinRoomRequestData,
+        LeaveRoomRequestData,
+        RoomSettingsRequestData,
+        TransferLeaderRequestData,
+        HelloRequestData,
+        KickPlayerRequestData,
+        SubscribeRoomRequestData,
+        CreateInviteRequestData,
+        UnbanPlayerRequestData,
+    },
+    protocol::{ self, HelloResponse, RoomClosed, ServerShuttingDown, RoomCredentialRotated, RoomUnsubscribed },
+    room::Invite,
+    view::PublicRoom,
+};
+use schemars::gen::SchemaSettings;
+use serde_json::{ json, Value };
+
+/*- JSON Schema of the JSON protocol, generated from the types the server
+    (de)serializes. Served on GET /schema and checked in at SCHEMA_PATH
+    for clients generating their types from it. Run `UPDATE_SCHEMA=1
+    cargo test` after changing any of these types. -*/
+
+/*- Constants -*/
+pub const SCHEMA_PATH:&str = "schema/protocol.schema.json";
+
+/*- Functions -*/
+pub fn generate() -> Value {
+    let mut gen = SchemaSettings::draft07().into_generator();
+
+    /*- `data` of each destination -*/
+    let requests = json!({
+        "create-room": gen.subschema_for::<CreateRoomRequestData>(),
+        "join-room": gen.subschema_for::<JoinRoomRequestData>(),
+        "leave-room": gen.subschema_for::<LeaveRoomRequestData>(),
+        "room-settings": gen.subschema_for::<RoomSettingsRequestData>(),
+        "transfer-leader": gen.subschema_for::<TransferLeaderRequestData>(),
+        "hello": gen.subschema_for::<HelloRequestData>(),
+        "kick-player": gen.subschema_for::<KickPlayerRequestData>(),
+        "subscribe-room": gen.subschema_for::<SubscribeRoomRequestData>(),
+        "create-invite": gen.subschema_for::<CreateInviteRequestData>(),
+        "unban-player": gen.subschema_for::<UnbanPlayerRequestData>(),
+    });
+
+    /*- `room` is sent as a string holding the room's JSON -*/
+    let room = json!({
+        "type": "string",
+        "contentMediaType": "application/json",
+        "contentSchema": gen.subschema_for::<PublicRoom>()
+    });
+
+    /*- Fields merged into a response, by what the request returns -*/
+    let responses = json!({
+        "room": {
+            "type": "object",
+            "required": ["room"],
+            "properties": { "room": room }
+        },
+        "hello": gen.subschema_for::<HelloResponse>(),
+        "membership": {
+            "type": "object",
+            "required": ["room", "private_id"],
+            "properties": { "room": room, "private_id": { "type": "string" } }
+        },
+        "invite": {
+            "type": "object",
+            "required": ["invite"],
+            "properties": { "invite": gen.subschema_for::<Invite>() }
+        },
+    });
+
+    /*- Fields merged into an event, by event name -*/
+    let events = json!({
+        "room-closed": gen.subschema_for::<RoomClosed>(),
+        "server-shutting-down": gen.subschema_for::<ServerShuttingDown>(),
+        "room-credential-rotated": gen.subschema_for::<RoomCredentialRotated>(),
+        "room-unsubscribed": gen.subschema_for::<RoomUnsubscribed>(),
+    });
+
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "Scrapbox websocket protocol",
+        "protocol_version": protocol::PROTOCOL_VERSION,
+        "request": gen.subschema_for::<GeneralRequest>(),
+        "response": {
+            "type": "object",
+            "required": ["type", "request_id", "status"],
+            "properties": {
+                "type": { "const": protocol::TYPE_RESPONSE },
+                "request_id": { "type": ["string", "null"] },
+                "status": { "type": "integer", "minimum": 0, "maximum": 65535 },
+                "error": { "type": "string" }
+            }
+        },
+        "event": {
+            "type": "object",
+            "required": ["type", "event"],
+            "properties": {
+                "type": { "const": protocol::TYPE_EVENT },
+                "event": { "type": "string" }
+            }
+        },
+        "requests": requests,
+        "responses": responses,
+        "events": events,
+        "definitions": gen.definitions(),
+    })
+}
+
+/*- Tests -*/
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ protocol::ResponseBody, room::Room, view::Membership };
+    use std::{ env, fs, path::Path };
+
+    #[test]
+    fn checked_in_schema_is_up_to_date() {
+        let generated = generate();
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(SCHEMA_PATH);
+
+        if env::var_os("UPDATE_SCHEMA").is_some() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, format!("{}\n", serde_json::to_string_pretty(&generated).unwrap())).unwrap();
+            return
+        };
+
+        /*- Compared as values, so key order doesn't matter -*/
+        let checked_in:Value = match fs::read_to_string(&path) {
+            Ok(e) => serde_json::from_str(&e).unwrap_or_default(),
+            Err(_) => Value::Null
+        };
+        assert!(
+            checked_in == generated,
+            "{SCHEMA_PATH} is out of date, regenerate it with `UPDATE_SCHEMA=1 cargo test`"
+        );
+    }
+
+    #[test]
+    fn every_destination_has_a_schema() {
+        let schema = generate();
+        let mut documented:Vec<&str> = schema["requests"].as_object().unwrap().keys().map(String::as_str).collect();
+        let mut destinations:Vec<&str> = RequestJsonType::DESTINATIONS.to_vec();
+        documented.sort();
+        destinations.sort();
+
+        assert_eq!(documented, destinations);
+    }
+
+    #[test]
+    fn responses_match_the_wire() {
+        let schema = generate();
+        let room = Room::default();
+        let invite = Invite { code: String::new(), single_use: false, expires_at: None };
+        let bodies = [
+            ("room", ResponseBody::Room(PublicRoom::of(&room))),
+            ("membership", ResponseBody::Membership(Membership::of(&room))),
+            ("invite", ResponseBody::Invite(invite)),
+        ];
+
+        for (name, body) in bodies {
+            let wire = protocol::response(None, Ok(body)).to_json();
+            let properties = schema["responses"][name]["properties"].as_object().unwrap();
+            for (key, property) in properties {
+                let value = &wire[key];
+                match property["type"].as_str() {
+                    Some("string") => assert!(value.is_string(), "{name}.{key} isn't sent as a string"),
+                    _ => assert!(value.is_object(), "{name}.{key} isn't sent as an object")
+                };
+                if property["contentMediaType"] == "application/json" {
+                    let content:Value = serde_json::from_str(value.as_str().unwrap()).unwrap();
+                    assert!(content.is_object(), "{name}.{key} doesn't hold a JSON object");
+                };
+            };
+        };
+    }
+}
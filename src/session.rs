@@ -0,0 +1,265 @@
+/*- Imports -*/
+use crate::{ bans, protocol::Encoding, req_utils, CONFIG, wrapper::PlayerRedisWrapper as PlayerWrpd, ws_status };
+use tracing::Span;
+use std::{
+    collections::{ HashSet, VecDeque },
+    net::SocketAddr,
+    time::{ Duration, Instant },
+};
+
+/*- Constants -*/
+// How long a checked token is trusted before it's sent to the account
+// manager again, so expired or revoked tokens stop working on open connections
+const AUTH_CACHE_TTL:Duration = Duration::from_secs(60);
+
+// Only strikes this recent count towards closing a connection, so
+// clients which burst now and then aren't closed for staying connected long
+const STRIKE_WINDOW:Duration = Duration::from_secs(60);
+
+/*- Structs, enums & unions -*/
+/*- State for one websocket connection. Owned by the connection task
+    and handed to every request handler, so nothing here is shared -*/
+#[derive(Debug)]
+pub struct Session {
+    // Address of the socket, also the key in the connection registry
+    pub addr: SocketAddr,
+
+    // Player this connection authenticated as, the token they used and
+    // when it was checked. Requests carrying the same token skip the
+    // account manager round trip until AUTH_CACHE_TTL has passed.
+    pub player: Option<PlayerWrpd>,
+    jwt: Option<String>,
+    jwt_checked_at: Instant,
+
+    // Rooms this connection is in, by public id. Roles aren't cached
+    // here, handlers check them on the room they read
+    pub rooms: HashSet<u32>,
+
+    // Flood protection
+    pub rate_limits: RateLimits,
+
+    // Wire format, None until the first request picks one
+    encoding: Option<Encoding>,
+
+    // When the client last sent a request, pings & pongs excluded
+    pub last_activity: Instant,
+
+    // When the client last answered one of our pings
+    pub last_pong: Instant,
+
+    // The connection's span, current when the session is created.
+    // The SUID is recorded on it once known
+    span: Span,
+}
+
+
+/*- Token buckets for this connection -*/
+#[derive(Debug)]
+pub struct RateLimits {
+    pub messages: TokenBucket,
+    pub room_creations: TokenBucket,
+
+    // When requests were rejected for being over a limit, oldest
+    // first. Strikes older than STRIKE_WINDOW are dropped
+    strikes: VecDeque<Instant>,
+}
+
+/*- Holds up to `capacity` tokens, refilling continuously -*/
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_second: f64,
+    last_refill: Instant,
+}
+
+/*- Method implementations -*/
+impl Session {
+    pub fn new(addr:SocketAddr) -> Self {
+        Self {
+            addr,
+            player: None,
+            jwt: None,
+            jwt_checked_at: Instant::now(),
+            rooms: HashSet::new(),
+            rate_limits: RateLimits::default(),
+            encoding: None,
+            last_activity: Instant::now(),
+            last_pong: Instant::now(),
+            span: Span::current(),
+        }
+    }
+
+    /*- Authenticate using the jwt sent with a request, reusing the player
+        from earlier requests if the token hasn't changed & was checked recently -*/
+    pub async fn authenticate(&mut self, jwt:&str) -> Result<PlayerWrpd, u16> {
+        if let (Some(player), Some(cached_jwt)) = (&self.player, &self.jwt) {
+            if bans::is_suid_banned(&player.player.suid) { return Err(ws_status::BANNED) };
+            if cached_jwt == jwt && self.jwt_checked_at.elapsed() < AUTH_CACHE_TTL { return Ok(player.clone()) };
+        };
+
+        let player = req_utils::authenticate(jwt, self.addr).await?;
+        self.span.record("suid", player.player.suid.as_str());
+        if bans::is_suid_banned(&player.player.suid) { return Err(ws_status::BANNED) };
+        self.player = Some(player.clone());
+        self.jwt = Some(jwt.to_string());
+        self.jwt_checked_at = Instant::now();
+
+        Ok(player)
+    }
+
+    /*- SUID of the authenticated player -*/
+    pub fn suid(&self) -> Option<&str> {
+        self.player.as_ref().map(|player| player.player.suid.as_str())
+    }
+
+    /*- The first request fixes the encoding, false if
+        `encoding` differs from what was negotiated -*/
+    pub fn negotiate(&mut self, encoding:Encoding) -> bool {
+        *self.encoding.get_or_insert(encoding) == encoding
+    }
+    pub fn encoding(&self) -> Encoding {
+        self.encoding.unwrap_or_default()
+    }
+
+    /*- Called for every inbound request -*/
+    pub fn touch(&mut self) {
+        self.last_activity = Instant::now();
+    }
+    pub fn idle_for(&self) -> Duration {
+        self.last_activity.elapsed()
+    }
+
+    /*- Heartbeat -*/
+    pub fn pong(&mut self) {
+        self.last_pong = Instant::now();
+    }
+    pub fn since_pong(&self) -> Duration {
+        self.last_pong.elapsed()
+    }
+
+    /*- Room membership -*/
+    pub fn is_in_room(&self, public_id:u32) -> bool {
+        self.rooms.contains(&public_id)
+    }
+    pub fn enter_room(&mut self, public_id:u32) {
+        self.rooms.insert(public_id);
+    }
+    pub fn exit_room(&mut self, public_id:u32) {
+        self.rooms.remove(&public_id);
+    }
+}
+
+impl TokenBucket {
+    pub fn new(capacity:f64, refill_per_second:f64) -> Self {
+        Self { capacity, tokens: capacity, refill_per_second, last_refill: Instant::now() }
+    }
+
+    /*- Take one token if there is one -*/
+    pub fn try_take(&mut self) -> bool {
+        self.refill();
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        }else {
+            false
+        }
+    }
+
+    /*- True once every token has been given back -*/
+    pub fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last_refill).as_secs_f64() * self.refill_per_second;
+        self.tokens = (self.tokens + refill).min(self.capacity);
+        self.last_refill = now;
+    }
+}
+
+impl RateLimits {
+    /*- Count a rejected request -*/
+    pub fn strike(&mut self) {
+        self.strike_at(Instant::now());
+    }
+    fn strike_at(&mut self, now:Instant) {
+        self.strikes.push_back(now);
+        self.forget_strikes(now);
+    }
+
+    /*- Rejected so often within STRIKE_WINDOW that the connection should be closed -*/
+    pub fn is_abusive(&mut self) -> bool {
+        self.forget_strikes(Instant::now());
+        self.strikes.len() >= CONFIG.rate_limit_strikes as usize
+    }
+
+    fn forget_strikes(&mut self, now:Instant) {
+        while self.strikes.front().is_some_and(|strike| now.duration_since(*strike) >= STRIKE_WINDOW) {
+            self.strikes.pop_front();
+        };
+    }
+
+    /*- Err with the rate-limit status if the bucket is empty -*/
+    pub fn take(bucket:&mut TokenBucket) -> Result<(), u16> {
+        match bucket.try_take() {
+            true => Ok(()),
+            false => Err(ws_status::RATE_LIMITED)
+        }
+    }
+}
+
+/*- Default settings -*/
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            messages: TokenBucket::new(CONFIG.messages.burst, CONFIG.messages.per_second),
+            room_creations: TokenBucket::new(CONFIG.room_creations.burst, CONFIG.room_creations.per_second),
+            strikes: VecDeque::new(),
+        }
+    }
+}
+
+/*- Tests -*/
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate_limits() -> RateLimits {
+        RateLimits {
+            messages: TokenBucket::new(1.0, 1.0),
+            room_creations: TokenBucket::new(1.0, 1.0),
+            strikes: VecDeque::new(),
+        }
+    }
+
+    #[test]
+    fn strikes_are_forgotten_after_the_window() {
+        let mut rate_limits = rate_limits();
+        let start = Instant::now();
+        for _ in 0..3 { rate_limits.strike_at(start) };
+        assert_eq!(rate_limits.strikes.len(), 3);
+
+        /*- Still inside the window -*/
+        rate_limits.strike_at(start + STRIKE_WINDOW / 2);
+        assert_eq!(rate_limits.strikes.len(), 4);
+
+        /*- The first three have expired, the fourth hasn't -*/
+        rate_limits.strike_at(start + STRIKE_WINDOW);
+        assert_eq!(rate_limits.strikes.len(), 2);
+    }
+
+    #[test]
+    fn occasional_strikes_never_pile_up() {
+        let mut rate_limits = rate_limits();
+        let start = Instant::now();
+        for minute in 0..1000 {
+            rate_limits.strike_at(start + STRIKE_WINDOW * minute);
+        };
+
+        assert_eq!(rate_limits.strikes.len(), 1);
+    }
+}
@@ -0,0 +1,76 @@
+/*- Imports -*/
+use crate::{ instance, room_store, protocol::{ self, Event, ServerShuttingDown }, PeerMap };
+use mongodb::Database;
+use tungstenite::Message;
+use tracing::{ error, info };
+use std::{
+    sync::atomic::{ AtomicBool, Ordering },
+    time::{ Duration, Instant },
+};
+
+/*- Constants -*/
+// How often we check if every peer has disconnected during the drain
+const DRAIN_POLL_INTERVAL:Duration = Duration::from_millis(250);
+
+/*- Statics -*/
+static SHUTTING_DOWN:AtomicBool = AtomicBool::new(false);
+
+/*- Functions -*/
+/*- Connections closed during shutdown keep their room seats for the restart -*/
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::Relaxed)
+}
+
+/*- Resolves when the process receives SIGINT or SIGTERM -*/
+pub async fn signal() -> () {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{ signal, SignalKind };
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(e) => e,
+            Err(_) => {
+                tokio::signal::ctrl_c().await.ok();
+                return
+            }
+        };
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = terminate.recv() => (),
+        };
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.ok();
+}
+
+/*- Notify every peer, persist rooms and wait for the drain period.
+    Called once the accept loop has stopped taking new connections -*/
+pub async fn drain(mongodb_connection:&Database, peer_map:&PeerMap, drain_period:Duration) -> () {
+    SHUTTING_DOWN.store(true, Ordering::Relaxed);
+
+    /*- Tell clients when it's worth trying to reconnect -*/
+    let frame = protocol::event(Event::ServerShuttingDown(ServerShuttingDown {
+        reconnect_after_ms: drain_period.as_millis() as u64
+    }));
+    peer_map.broadcast_encoded(|encoding| frame.encode(encoding));
+
+    /*- Keep our rooms so players can rejoin them after the restart -*/
+    match room_store::suspend_rooms_of(mongodb_connection, instance::id()).await {
+        Ok(amount) => info!(amount, "Suspended rooms"),
+        Err(status) => error!(status, "Could not suspend rooms")
+    };
+
+    /*- Wait for peers to leave, but no longer than the drain period -*/
+    let start = Instant::now();
+    while start.elapsed() < drain_period && peer_map.len() > 0 {
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    };
+
+    /*- Close whatever is left, and give the close frames a moment to flush -*/
+    peer_map.broadcast(&Message::Close(None));
+    tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+
+    /*- Let the next instance to start resume our rooms right away -*/
+    instance::stop(mongodb_connection).await;
+}
@@ -0,0 +1,37 @@
+/*- Imports -*/
+use crate::CONFIG;
+use tokio_native_tls::{ native_tls, TlsAcceptor };
+use std::fs;
+
+/*- Functions -*/
+/*- Build a TLS acceptor from the PEM certificate & PKCS#8 key pointed to
+    by the config. Returns None when neither is set, which means we serve
+    plain ws:// and leave TLS termination to whatever is in front of us.
+    The config already checked that both files exist, Err is for files
+    which can't be read or don't hold a valid certificate & key. -*/
+pub fn load_acceptor() -> Result<Option<TlsAcceptor>, String> {
+    let (cert_path, key_path) = match (&CONFIG.tls_cert_path, &CONFIG.tls_key_path) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return Ok(None)
+    };
+
+    /*- Read & parse certificate -*/
+    let cert = match fs::read(cert_path) {
+        Ok(e) => e,
+        Err(e) => return Err(format!("Could not read TLS certificate {}: {e}", cert_path.display()))
+    };
+    let key = match fs::read(key_path) {
+        Ok(e) => e,
+        Err(e) => return Err(format!("Could not read TLS key {}: {e}", key_path.display()))
+    };
+    let identity = match native_tls::Identity::from_pkcs8(&cert, &key) {
+        Ok(e) => e,
+        Err(e) => return Err(format!("Invalid TLS certificate or key: {e}"))
+    };
+
+    /*- Create acceptor -*/
+    match native_tls::TlsAcceptor::new(identity) {
+        Ok(acceptor) => Ok(Some(TlsAcceptor::from(acceptor))),
+        Err(e) => Err(format!("Could not create TLS acceptor: {e}"))
+    }
+}
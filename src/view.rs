@@ -0,0 +1,80 @@
+/*- Imports -*/
+use crate::{ player::GameStatistics, room::Room, wrapper::PlayerRedisWrapper as PlayerWrpd };
+use serde_derive::{ Serialize, Deserialize };
+use schemars::JsonSchema;
+use std::fmt;
+
+/*- What clients get to see of rooms and players. `Room` and `PlayerRedisWrapper`
+    are what's stored in mongodb and carry socket addresses & internal
+    bookkeeping, so they must never be sent over the websocket themselves -*/
+
+/*- Structs, enums & unions -*/
+/*- Sent only to players admitted to the room, `private_id` is
+    what grants access to the room channel -*/
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Membership {
+    pub room        : PublicRoom,
+    pub private_id  : String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct PublicRoom {
+    pub public_id   : u32,
+    pub players     : Vec<PublicPlayer>,
+    pub max_players : u8,
+    pub started     : bool,
+    pub private     : bool,
+
+    // What it takes to join
+    pub password_protected: bool,
+    pub invite_only : bool,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct PublicPlayer {
+    pub suid          : String,
+    pub displayname   : String,
+    pub board_position: [u8; 2],
+    pub leader        : bool,
+    pub statistics    : GameStatistics,
+}
+
+/*- Method implementations -*/
+impl Membership {
+    pub fn of(room:&Room) -> Self {
+        Self { room: PublicRoom::of(room), private_id: room.private_id.clone() }
+    }
+}
+
+impl PublicRoom {
+    pub fn of(room:&Room) -> Self {
+        Self {
+            public_id: room.public_id,
+            players: room.players.iter().map(|player| PublicPlayer::of(player, room)).collect(),
+            max_players: room.max_players,
+            started: room.started,
+            private: room.private,
+            password_protected: room.password_hash.is_some(),
+            invite_only: room.invite_only,
+        }
+    }
+}
+
+impl PublicPlayer {
+    pub fn of(player:&PlayerWrpd, room:&Room) -> Self {
+        Self {
+            suid: player.player.suid.clone(),
+            displayname: player.player.displayname.clone(),
+            board_position: player.local_data.board_position,
+            leader: room.is_leader(&player.player.suid),
+            statistics: player.player.statistics,
+        }
+    }
+}
+
+/*- Displayed as its JSON, which is how `room` is sent -*/
+impl fmt::Display for PublicRoom {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", serde_json::to_string(self).unwrap_or_default())
+    }
+}
@@ -4,3 +4,28 @@ pub const PARSE_ACCOUNT_API_RES_TEXT:u16 = 602u16;
 pub const PLAYER_PARSE:u16 = 603u16;
 pub const CORRUPTED_ROOM:u16 = 604u16;
 pub const ROOM_UPDATE_PLAYERS:u16 = 605u16;
+pub const ROOM_NOT_FOUND:u16 = 606u16;
+pub const ROOM_FULL:u16 = 607u16;
+pub const ROOM_UPDATE_CONFLICT:u16 = 608u16;
+pub const PLAYER_NOT_IN_ROOM:u16 = 609u16;
+pub const NOT_ROOM_LEADER:u16 = 610u16;
+pub const INVALID_ROOM_SETTINGS:u16 = 611u16;
+pub const DATABASE_ERROR:u16 = 612u16;
+pub const NO_FREE_ROOM_ID:u16 = 613u16;
+pub const MONGODB_UNAVAILABLE:u16 = 614u16;
+pub const ACCOUNT_MANAGER_UNAVAILABLE:u16 = 615u16;
+pub const RATE_LIMITED:u16 = 616u16;
+pub const ROOM_STARTED:u16 = 617u16;
+pub const INVALID_REQUEST:u16 = 618u16;
+pub const UNSUPPORTED_BINARY_VERSION:u16 = 619u16;
+pub const UNSUPPORTED_PROTOCOL_VERSION:u16 = 620u16;
+pub const CANNOT_KICK_SELF:u16 = 621u16;
+pub const INVALID_ROOM_CREDENTIAL:u16 = 622u16;
+pub const WRONG_ROOM_PASSWORD:u16 = 623u16;
+pub const INVITE_REQUIRED:u16 = 624u16;
+pub const INVALID_INVITE:u16 = 625u16;
+pub const BANNED_FROM_ROOM:u16 = 626u16;
+pub const BANNED:u16 = 627u16;
+pub const TOO_MANY_ROOMS:u16 = 628u16;
+pub const PAYLOAD_TOO_LARGE:u16 = 629u16;
+pub const ENCODE_FAILED:u16 = 630u16;